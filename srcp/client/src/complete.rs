@@ -0,0 +1,151 @@
+use editor::Editor;
+
+// What Tab last completed, so that pressing it again can move on to the
+// next candidate.
+pub struct Completion {
+    // Where the completed word starts.
+    start: usize,
+    candidates: Vec<String>,
+    index: usize,
+    // The line as it was completed. If it has been edited since, Tab
+    // starts a new completion.
+    line: String,
+}
+
+impl Completion {
+    // Completes the word before the cursor: a command name at the start of
+    // a line, a room after /join, and otherwise one of `nicks`. A nickname
+    // at the start of a line is addressed with a colon. Returns None if
+    // nothing matches.
+    pub fn start(line: &mut Editor, nicks: &[String], rooms: &[String]) -> Option<Completion> {
+        let before = line.before_cursor();
+        let start = before.rfind(char::is_whitespace)
+            .map_or(0, |i| i + before[i..].chars().next().map_or(1, char::len_utf8));
+        let word = before[start..].to_lowercase();
+        let previous: Vec<String> = before[..start].split_whitespace()
+            .map(str::to_lowercase)
+            .collect();
+
+        let commands: Vec<String>;
+        let (names, suffix): (Vec<&str>, &str) = if start == 0 && word.starts_with('/') && !word.starts_with("//") {
+            commands = ::input::COMMANDS.iter().map(|c| format!("/{}", c)).collect();
+            (commands.iter().map(String::as_str).collect(), " ")
+        } else if previous == ["/join"] {
+            let rooms = rooms.iter()
+                .map(String::as_str)
+                .filter(|room| *room != ::DEFAULT_ROOM && !room.starts_with(::WHISPER_SIGIL));
+            (rooms.collect(), " ")
+        } else if start == 0 {
+            (nicks.iter().map(String::as_str).collect(), ": ")
+        } else {
+            (nicks.iter().map(String::as_str).collect(), " ")
+        };
+
+        let candidates: Vec<String> = names.into_iter()
+            .filter(|name| name.to_lowercase().starts_with(&word))
+            .map(|name| format!("{}{}", name, suffix))
+            .collect();
+
+        let first = candidates.first()?.clone();
+        line.replace_to_cursor(start, &first);
+
+        Some(Completion {
+            start,
+            candidates,
+            index: 0,
+            line: line.text().to_string(),
+        })
+    }
+
+    // Replaces the last completion with the next candidate. Returns false
+    // if the line has changed since, in which case nothing is done.
+    pub fn next(&mut self, line: &mut Editor) -> bool {
+        if line.text() != self.line {
+            return false;
+        }
+
+        self.index = (self.index + 1) % self.candidates.len();
+        line.replace_to_cursor(self.start, &self.candidates[self.index]);
+        self.line = line.text().to_string();
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(text: &str) -> Editor {
+        let mut line = Editor::new();
+        line.set(text);
+
+        line
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn nicks_at_the_start_are_addressed() {
+        let mut line = typed("AL");
+        assert!(Completion::start(&mut line, &names(&["bob", "alice"]), &[]).is_some());
+        assert_eq!(line.text(), "alice: ");
+
+        let mut line = typed("hi al");
+        assert!(Completion::start(&mut line, &names(&["bob", "alice"]), &[]).is_some());
+        assert_eq!(line.text(), "hi alice ");
+    }
+
+    #[test]
+    fn nothing_to_complete() {
+        let mut line = typed("hi zed");
+        assert!(Completion::start(&mut line, &names(&["bob", "alice"]), &[]).is_none());
+        assert_eq!(line.text(), "hi zed");
+    }
+
+    #[test]
+    fn tab_again_cycles() {
+        let nicks = names(&["anna", "bob", "alice"]);
+        let mut line = typed("hi a");
+        let mut completion = Completion::start(&mut line, &nicks, &[]).expect("completion");
+        assert_eq!(line.text(), "hi anna ");
+
+        assert!(completion.next(&mut line));
+        assert_eq!(line.text(), "hi alice ");
+        assert!(completion.next(&mut line));
+        assert_eq!(line.text(), "hi anna ");
+
+        // Editing in between starts over.
+        line.insert('x');
+        assert!(!completion.next(&mut line));
+        assert_eq!(line.text(), "hi anna x");
+    }
+
+    #[test]
+    fn commands_and_rooms() {
+        let mut line = typed("/jo");
+        assert!(Completion::start(&mut line, &[], &[]).is_some());
+        assert_eq!(line.text(), "/join ");
+
+        let rooms = names(&[::DEFAULT_ROOM, "@bob", "general", "games"]);
+        let mut line = typed("/join g");
+        let mut completion = Completion::start(&mut line, &names(&["gus"]), &rooms).expect("completion");
+        assert_eq!(line.text(), "/join general ");
+        assert!(completion.next(&mut line));
+        assert_eq!(line.text(), "/join games ");
+        assert!(completion.next(&mut line));
+        assert_eq!(line.text(), "/join general ");
+
+        let mut line = typed("/join @");
+        assert!(Completion::start(&mut line, &[], &rooms).is_none());
+    }
+
+    #[test]
+    fn after_wide_whitespace() {
+        let mut line = typed("日本\u{3000}al");
+        assert!(Completion::start(&mut line, &names(&["alice"]), &[]).is_some());
+        assert_eq!(line.text(), "日本\u{3000}alice ");
+    }
+}
@@ -0,0 +1,178 @@
+use ::toml;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use srcp_client::{Security, TlsOptions};
+
+const DEFAULT_ADDRESS: &str = "localhost:6667";
+const CONFIG_FILE: &str = "client.toml";
+
+pub const USAGE: &str = "\
+usage: client [OPTIONS] [ADDRESS]
+
+options:
+    --config PATH        read settings from PATH instead of ~/.config/srcp/client.toml
+    --nick NAME          identify as NAME on connect
+    --alt-nick NAME      try NAME if the nickname is taken (repeatable)
+    --join ROOM          join ROOM on connect (repeatable)
+    --tls                connect over TLS
+    --ca-file PATH       also trust the certificate authorities in PATH (implies --tls)
+    --pin FINGERPRINT    trust only the certificate with this SHA-256 fingerprint (implies --tls)
+    --help               show this message";
+
+// Client settings. They come from the config file, for example:
+//
+//     server = "chat.example.com:6697"
+//     nick = "alice"
+//     alt_nicks = ["alice_", "alice__"]
+//     rooms = ["general", "rust"]
+//     member_list = false
+//     auto_away = 10
+//
+//     [tls]
+//     enabled = true
+//     ca_file = "/etc/srcp/ca.pem"
+//     pin = "9A:89:...:1F"
+//
+// and are then overridden by the command line. Every setting is optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Option<String>,
+    pub nick: Option<String>,
+    pub alt_nicks: Vec<String>,
+    pub rooms: Vec<String>,
+    // Whether to show who is in the current room; on unless turned off.
+    pub member_list: Option<bool>,
+    // Minutes without typing after which to mark ourselves away.
+    pub auto_away: Option<u64>,
+    pub tls: TlsConfig,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub ca_file: Option<String>,
+    pub pin: Option<String>,
+}
+
+impl Config {
+    // Reads the config file and applies the command line on top of it.
+    // Returns Ok(None) if the user only asked for help.
+    pub fn load() -> Result<Option<Config>, String> {
+        let args: Vec<String> = env::args().skip(1).collect();
+
+        if args.iter().any(|a| a == "--help" || a == "-h") {
+            return Ok(None);
+        }
+
+        // --config has to be known before anything else is applied.
+        let explicit = args.iter()
+            .position(|a| a == "--config")
+            .map(|i| args.get(i + 1).cloned().ok_or("--config needs a path"))
+            .transpose()?;
+
+        let mut config = match explicit {
+            Some(path) => Config::read(&PathBuf::from(path))?,
+            None => match config_dir() {
+                Ok(dir) if dir.join(CONFIG_FILE).exists() => Config::read(&dir.join(CONFIG_FILE))?,
+                _ => Config::default(),
+            },
+        };
+
+        config.apply_args(args)?;
+
+        Ok(Some(config))
+    }
+
+    fn read(path: &PathBuf) -> Result<Config, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| format!("cannot parse {}: {}", path.display(), e))
+    }
+
+    fn apply_args(&mut self, args: Vec<String>) -> Result<(), String> {
+        // Rooms and alternative nicknames given on the command line
+        // replace those from the file rather than adding to them.
+        let mut alt_nicks = vec![];
+        let mut rooms = vec![];
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+
+            match arg.as_str() {
+                "--config" => {
+                    value()?;
+                },
+                "--nick" => self.nick = Some(value()?),
+                "--alt-nick" => alt_nicks.push(value()?),
+                "--join" => rooms.push(value()?),
+                "--tls" => self.tls.enabled = true,
+                "--ca-file" => self.tls.ca_file = Some(value()?),
+                "--pin" => self.tls.pin = Some(value()?),
+                _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+                _ => self.server = Some(arg),
+            }
+        }
+
+        if !alt_nicks.is_empty() {
+            self.alt_nicks = alt_nicks;
+        }
+        if !rooms.is_empty() {
+            self.rooms = rooms;
+        }
+
+        Ok(())
+    }
+
+    pub fn address(&self) -> String {
+        self.server.clone().unwrap_or_else(|| String::from(DEFAULT_ADDRESS))
+    }
+
+    // Either of a CA file or a pin implies TLS.
+    pub fn security(&self) -> Security {
+        let tls = &self.tls;
+
+        if tls.enabled || tls.ca_file.is_some() || tls.pin.is_some() {
+            Security::Tls(TlsOptions {
+                ca_file: tls.ca_file.clone(),
+                pin: tls.pin.clone(),
+            })
+        } else {
+            Security::Plain
+        }
+    }
+
+    pub fn member_list(&self) -> bool {
+        self.member_list.unwrap_or(true)
+    }
+
+    pub fn auto_away(&self) -> Option<Duration> {
+        self.auto_away.map(|minutes| Duration::from_secs(minutes * 60))
+    }
+
+    // The names to identify with, in order of preference.
+    pub fn nicks(&self) -> Vec<String> {
+        self.nick.iter().chain(self.alt_nicks.iter()).cloned().collect()
+    }
+}
+
+// Where the client keeps its files: $XDG_CONFIG_HOME/srcp, falling back
+// to ~/.config/srcp.
+pub fn config_dir() -> io::Result<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        return Ok(PathBuf::from(dir).join("srcp"));
+    }
+
+    env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".config").join("srcp"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))
+}
@@ -0,0 +1,233 @@
+use ::unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// The line being typed in the input window. The cursor is a byte offset
+// into the text and always sits on a character boundary.
+#[derive(Default)]
+pub struct Editor {
+    text: String,
+    cursor: usize,
+    // Columns scrolled off the left edge when the line is wider than the
+    // window.
+    offset: usize,
+}
+
+impl Editor {
+    pub fn new() -> Editor {
+        Editor::default()
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn clear(&mut self) {
+        *self = Editor::default();
+    }
+
+    // Replaces the line, leaving the cursor at the end.
+    pub fn set(&mut self, text: &str) {
+        self.text = text.to_string();
+        self.cursor = self.text.len();
+    }
+
+    // The text before the cursor.
+    pub fn before_cursor(&self) -> &str {
+        &self.text[..self.cursor]
+    }
+
+    // Replaces the text from `start` up to the cursor, leaving the cursor
+    // after the replacement.
+    pub fn replace_to_cursor(&mut self, start: usize, text: &str) {
+        self.text.replace_range(start..self.cursor, text);
+        self.cursor = start + text.len();
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(start) = self.prev_boundary() {
+            self.text.drain(start..self.cursor);
+            self.cursor = start;
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if let Some(end) = self.next_boundary() {
+            self.text.drain(self.cursor..end);
+        }
+    }
+
+    pub fn left(&mut self) {
+        if let Some(start) = self.prev_boundary() {
+            self.cursor = start;
+        }
+    }
+
+    pub fn right(&mut self) {
+        if let Some(end) = self.next_boundary() {
+            self.cursor = end;
+        }
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    // Deletes the word before the cursor along with any whitespace
+    // between it and the cursor.
+    pub fn delete_word(&mut self) {
+        let before = &self.text[..self.cursor];
+        let trimmed = before.trim_end();
+        let start = trimmed.rfind(char::is_whitespace)
+            .map(|i| i + trimmed[i..].chars().next().map_or(1, char::len_utf8))
+            .unwrap_or(0);
+
+        self.text.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    pub fn kill_to_start(&mut self) {
+        self.text.drain(..self.cursor);
+        self.cursor = 0;
+    }
+
+    pub fn kill_to_end(&mut self) {
+        self.text.truncate(self.cursor);
+    }
+
+    // The part of the line that fits in `width` columns, scrolled so that
+    // the cursor is visible, and the column the cursor is in.
+    pub fn visible(&mut self, width: usize) -> (String, usize) {
+        if width == 0 {
+            return (String::new(), 0);
+        }
+
+        let total = self.text.width();
+        let cursor = self.text[..self.cursor].width();
+
+        // Don't leave empty space on the right while text is hidden on the
+        // left, and keep the cursor on screen.
+        self.offset = std::cmp::min(self.offset, (total + 1).saturating_sub(width));
+        if cursor < self.offset {
+            self.offset = cursor;
+        } else if cursor >= self.offset + width {
+            self.offset = cursor + 1 - width;
+        }
+
+        let mut shown = String::new();
+        let mut col = 0;
+        for c in self.text.chars() {
+            let w = c.width().unwrap_or(0);
+            if col >= self.offset && col + w <= self.offset + width {
+                shown.push(c);
+            } else if col < self.offset && col + w > self.offset {
+                // A wide character cut in half by the left edge.
+                shown.push(' ');
+            }
+            col += w;
+        }
+
+        (shown, cursor - self.offset)
+    }
+
+    fn prev_boundary(&self) -> Option<usize> {
+        self.text[..self.cursor].char_indices().next_back().map(|(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> Option<usize> {
+        self.text[self.cursor..].chars().next().map(|c| self.cursor + c.len_utf8())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(text: &str) -> Editor {
+        let mut editor = Editor::new();
+        for c in text.chars() {
+            editor.insert(c);
+        }
+
+        editor
+    }
+
+    #[test]
+    fn cursor_moves_by_character() {
+        let mut editor = typed("héllo");
+        for _ in 0..4 {
+            editor.left();
+        }
+        assert_eq!(editor.before_cursor(), "h");
+
+        editor.right();
+        assert_eq!(editor.before_cursor(), "hé");
+
+        editor.backspace();
+        assert_eq!(editor.text(), "hllo");
+        editor.delete();
+        assert_eq!(editor.text(), "hlo");
+        assert_eq!(editor.before_cursor(), "h");
+    }
+
+    #[test]
+    fn cursor_stops_at_the_ends() {
+        let mut editor = typed("日本");
+        editor.right();
+        editor.delete();
+        assert_eq!(editor.text(), "日本");
+
+        editor.home();
+        editor.left();
+        editor.backspace();
+        assert_eq!(editor.text(), "日本");
+        assert_eq!(editor.before_cursor(), "");
+    }
+
+    #[test]
+    fn delete_word_takes_trailing_whitespace() {
+        let mut editor = typed("say 日本 語  ");
+        editor.delete_word();
+        assert_eq!(editor.text(), "say 日本 ");
+        editor.delete_word();
+        assert_eq!(editor.text(), "say ");
+        editor.delete_word();
+        assert_eq!(editor.text(), "");
+        editor.delete_word();
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn delete_word_after_wide_whitespace() {
+        let mut editor = typed("日本\u{3000}語");
+        editor.delete_word();
+        assert_eq!(editor.text(), "日本\u{3000}");
+    }
+
+    #[test]
+    fn delete_word_in_the_middle() {
+        let mut editor = typed("one two three");
+        for _ in 0.."three".len() {
+            editor.left();
+        }
+        editor.delete_word();
+        assert_eq!(editor.text(), "one three");
+        assert_eq!(editor.before_cursor(), "one ");
+    }
+
+    #[test]
+    fn visible_keeps_the_cursor_on_screen() {
+        let mut editor = typed("日本語");
+        assert_eq!(editor.visible(4), (String::from(" 語"), 3));
+
+        editor.home();
+        assert_eq!(editor.visible(4), (String::from("日本"), 0));
+    }
+}
@@ -0,0 +1,322 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use common::Command;
+use config::config_dir;
+use input::Input;
+
+const HISTORY_FILE: &str = "history";
+// How many lines are kept, in memory and on disk.
+const HISTORY_SIZE: usize = 1000;
+
+// Lines the user has entered, oldest first, and where they are while
+// browsing through them.
+pub struct History {
+    entries: Vec<String>,
+    // The entry being shown while browsing, and the line that was being
+    // typed before browsing started.
+    position: Option<usize>,
+    draft: String,
+    file: Option<PathBuf>,
+}
+
+// A reverse incremental search through the history.
+pub struct Search {
+    pub query: String,
+    // The entry matching the query, if any.
+    pub found: Option<usize>,
+    // The line that was being typed before searching, restored if the
+    // search is cancelled.
+    pub draft: String,
+}
+
+impl History {
+    // Loads the history file. History is a convenience, so a missing or
+    // unreadable file just means starting from nothing.
+    pub fn load() -> History {
+        History::from_file(config_dir().ok().map(|dir| dir.join(HISTORY_FILE)))
+    }
+
+    fn from_file(file: Option<PathBuf>) -> History {
+        let mut entries: Vec<String> = file.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|contents| contents.lines().map(String::from).collect())
+            .unwrap_or_default();
+
+        if entries.len() > HISTORY_SIZE {
+            entries.drain(..entries.len() - HISTORY_SIZE);
+            if let Some(ref path) = file {
+                let _ = fs::write(path, entries.join("\n") + "\n");
+            }
+        }
+
+        History {
+            entries,
+            position: None,
+            draft: String::new(),
+            file,
+        }
+    }
+
+    // Remembers a line. Lines that shouldn't end up on disk, like
+    // whispers, are only kept for this session. After failing to write
+    // the file once, history is no longer saved.
+    pub fn add(&mut self, line: &str, save: bool) -> io::Result<()> {
+        self.position = None;
+
+        let line = line.trim();
+        if line.is_empty() || self.entries.last().map(String::as_str) == Some(line) {
+            return Ok(());
+        }
+
+        self.entries.push(line.to_string());
+        if self.entries.len() > HISTORY_SIZE {
+            self.entries.remove(0);
+        }
+
+        if save {
+            self.append(line).inspect_err(|_| self.file = None)?;
+        }
+
+        Ok(())
+    }
+
+    fn append(&self, line: &str) -> io::Result<()> {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+
+        writeln!(file, "{}", line)
+    }
+
+    // The entry before the one being shown. `current` is what's being
+    // typed, kept to come back to.
+    pub fn previous(&mut self, current: &str) -> Option<&str> {
+        let index = match self.position {
+            Some(0) => return None,
+            Some(i) => i - 1,
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            },
+        };
+
+        self.position = Some(index);
+        Some(&self.entries[index])
+    }
+
+    // The entry after the one being shown, or the line that was being
+    // typed once past the newest entry.
+    pub fn next(&mut self) -> Option<&str> {
+        let index = self.position?;
+
+        if index + 1 < self.entries.len() {
+            self.position = Some(index + 1);
+            Some(&self.entries[index + 1])
+        } else {
+            self.position = None;
+            Some(&self.draft)
+        }
+    }
+
+    pub fn get(&self, index: usize) -> &str {
+        &self.entries[index]
+    }
+
+    // The newest entry older than `before` that contains `query`.
+    fn search(&self, query: &str, before: usize) -> Option<usize> {
+        let before = std::cmp::min(before, self.entries.len());
+        self.entries[..before].iter().rposition(|entry| entry.contains(query))
+    }
+}
+
+// Whether a line belongs in the history file. Whispers are end to end
+// encrypted, and passwords are secret, so they stay out of it.
+pub fn saved(input: &Input) -> bool {
+    match *input {
+        Input::Send(Command::Whisper(..)) | Input::Send(Command::Admin(_)) | Input::Send(Command::Oper(..)) => false,
+        Input::Send(Command::Action(ref to, _)) => !to.starts_with('@'),
+        _ => true,
+    }
+}
+
+impl Search {
+    pub fn new(draft: &str) -> Search {
+        Search {
+            query: String::new(),
+            found: None,
+            draft: draft.to_string(),
+        }
+    }
+
+    // Extends the query, staying on the current match if it still matches.
+    pub fn push(&mut self, c: char, history: &History) {
+        self.query.push(c);
+        let from = self.found.map_or(usize::MAX, |i| i + 1);
+        self.found = history.search(&self.query, from);
+    }
+
+    // Shortens the query and searches again from the newest entry.
+    pub fn pop(&mut self, history: &History) {
+        self.query.pop();
+        self.found = history.search(&self.query, usize::MAX);
+    }
+
+    // Moves on to an older match, if there is one.
+    pub fn older(&mut self, history: &History) {
+        let from = self.found.unwrap_or(usize::MAX);
+        if let Some(i) = history.search(&self.query, from) {
+            self.found = Some(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    use input;
+
+    fn history(lines: &[&str]) -> History {
+        let mut history = History::from_file(None);
+        for line in lines {
+            history.add(line, false).expect("add");
+        }
+
+        history
+    }
+
+    // A history file of its own, removed again by the test.
+    fn scratch_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("srcp-history-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn previous_and_next() {
+        let mut history = history(&["one", "two", "three"]);
+
+        assert_eq!(history.next(), None);
+        assert_eq!(history.previous("typing"), Some("three"));
+        assert_eq!(history.previous("three"), Some("two"));
+        assert_eq!(history.previous("two"), Some("one"));
+        assert_eq!(history.previous("one"), None);
+        assert_eq!(history.next(), Some("two"));
+        assert_eq!(history.next(), Some("three"));
+        assert_eq!(history.next(), Some("typing"));
+        assert_eq!(history.next(), None);
+    }
+
+    #[test]
+    fn repeats_and_blank_lines_are_skipped() {
+        let mut history = history(&["one", "  ", "one", " two "]);
+
+        assert_eq!(history.previous(""), Some("two"));
+        assert_eq!(history.previous(""), Some("one"));
+        assert_eq!(history.previous(""), None);
+    }
+
+    #[test]
+    fn whispers_and_passwords_are_not_saved() {
+        let saved = |line: &str| saved(&input::parse(line, "general"));
+
+        assert!(saved("hello"));
+        assert!(saved("/me waves"));
+        assert!(saved("/join rust"));
+        assert!(!saved("/msg bob hello"));
+        assert!(!saved("/msg nickserv IDENTIFY hunter2"));
+        assert!(!saved("/admin hunter2"));
+        assert!(!saved("/oper root hunter2"));
+    }
+
+    #[test]
+    fn saved_lines_are_loaded_again() {
+        let path = scratch_file("saved");
+
+        let mut history = History::from_file(Some(path.clone()));
+        history.add("hello", true).expect("add");
+        history.add("/msg bob secret", false).expect("add");
+        history.add("/join rust", true).expect("add");
+
+        // Unsaved lines are still there until the client exits.
+        assert_eq!(history.previous(""), Some("/join rust"));
+        assert_eq!(history.previous(""), Some("/msg bob secret"));
+
+        let mut history = History::from_file(Some(path.clone()));
+        assert_eq!(history.previous(""), Some("/join rust"));
+        assert_eq!(history.previous(""), Some("hello"));
+        assert_eq!(history.previous(""), None);
+
+        fs::remove_file(&path).expect("remove");
+    }
+
+    #[test]
+    fn loading_keeps_the_newest_lines() {
+        let path = scratch_file("trimmed");
+        let lines: Vec<String> = (0..HISTORY_SIZE + 5).map(|i| i.to_string()).collect();
+        fs::write(&path, lines.join("\n") + "\n").expect("write");
+
+        let mut history = History::from_file(Some(path.clone()));
+        assert_eq!(history.entries.len(), HISTORY_SIZE);
+        assert_eq!(history.get(0), "5");
+        assert_eq!(history.previous(""), Some((HISTORY_SIZE + 4).to_string().as_str()));
+        assert_eq!(fs::read_to_string(&path).expect("read").lines().count(), HISTORY_SIZE);
+
+        fs::remove_file(&path).expect("remove");
+    }
+
+    #[test]
+    fn search_steps_to_older_matches() {
+        let history = history(&["/join rust", "hello", "/join go", "/join rust"]);
+        let mut search = Search::new("typing");
+
+        search.push('j', &history);
+        assert_eq!(search.found, Some(3));
+        search.older(&history);
+        assert_eq!(search.found, Some(2));
+        search.older(&history);
+        assert_eq!(search.found, Some(0));
+
+        // Nothing older matches, so the search stays put.
+        search.older(&history);
+        assert_eq!(search.found, Some(0));
+
+        // A longer query keeps the match while it still fits.
+        search.push('o', &history);
+        assert_eq!(search.found, Some(0));
+
+        search.pop(&history);
+        assert_eq!(search.found, Some(3));
+        assert_eq!(search.draft, "typing");
+    }
+
+    #[test]
+    fn search_without_a_match() {
+        let history = history(&["hello"]);
+        let mut search = Search::new("");
+
+        search.push('z', &history);
+        assert_eq!(search.found, None);
+        search.older(&history);
+        assert_eq!(search.found, None);
+    }
+}
@@ -0,0 +1,193 @@
+use ::common::Command;
+
+pub const HELP: &[&str] = &[
+    "Type a message to say it in the current room, or to whisper in a conversation. Commands:",
+    "  /join ROOM             join (or create) a room",
+    "  /leave [ROOM]          leave a room or close a conversation, the current one by default",
+    "  /msg NICK MESSAGE      whisper to someone",
+    "  /me ACTION             do something in the current room or conversation",
+    "  /shout MESSAGE         say something in every room you are in; /shout /me ACTION does it",
+    "  /admin PASSWORD        give the server's admin password",
+    "  /announce MESSAGE      tell everyone on the server (admins and operators only)",
+    "  /list [ROOM]           list the rooms, or the people in a room",
+    "  /nick NICK             change your nickname",
+    "  /topic [TOPIC]         show or set the current room's topic",
+    "  /kick NICK             remove someone from the current room",
+    "  /whois NICK            show what the server tells about someone",
+    "  /away [MESSAGE]        say why you are away, or that you are back",
+    "  /verify NICK           mark NICK's whisper key as verified",
+    "  /oper NAME PASSWORD    log in as a server operator, for the commands below",
+    "  /kill NICK             disconnect someone",
+    "  /ban [RANGE]           list bans, or ban an address range like 192.0.2.0/24",
+    "  /unban RANGE           lift a ban",
+    "  /close ROOM            kick everyone out of a room",
+    "  /rename NICK NEWNICK   change someone's nickname",
+    "  /reload                have the server read its config file again",
+    "  /quit                  disconnect and exit",
+    "  /help                  show this message",
+    "Start a message with // to say something beginning with a slash.",
+];
+
+// Every command name, for completion.
+pub const COMMANDS: &[&str] = &[
+    "admin", "announce", "away", "ban", "close", "exit", "help", "join", "kick", "kill", "leave", "list", "me", "msg", "nick",
+    "oper", "part", "quit", "reload", "rename", "shout", "topic", "unban", "verify", "whisper", "whois",
+];
+
+// What the user asked for with a line of input.
+pub enum Input {
+    // Something to send to the server.
+    Send(Command),
+    // Commands handled by the client itself.
+    Verify(String),
+    Close(String),
+    Help,
+    Quit,
+    // The line was not understood; nothing is sent.
+    Invalid(String),
+}
+
+// Interprets a line typed while `room` is the current room. Plain text is
+// said in that room; lines starting with a slash are commands.
+pub fn parse(line: &str, room: &str) -> Input {
+    let line = line.trim();
+
+    if line.is_empty() {
+        return Input::Invalid(String::new());
+    }
+
+    // "//text" says "/text".
+    if !line.starts_with('/') || line.starts_with("//") {
+        let text = if line.starts_with("//") { &line[1..] } else { line };
+
+        if room == ::DEFAULT_ROOM {
+            return Input::Invalid(String::from("You are not in a room. /join one first, or see /help."));
+        }
+        if room.starts_with(::WHISPER_SIGIL) {
+            return Input::Send(Command::Whisper(room[1..].to_string(), text.to_string()));
+        }
+        return Input::Send(Command::Say(room.to_string(), text.to_string()));
+    }
+
+    let mut words = line[1..].splitn(2, char::is_whitespace);
+    let name = words.next().unwrap_or_default().to_lowercase();
+    let rest = words.next().unwrap_or_default().trim();
+    let args: Vec<&str> = rest.split_whitespace().collect();
+
+    let usage = |text: &str| Input::Invalid(format!("Usage: {}", text));
+
+    match name.as_str() {
+        "join" => match args.len() {
+            1 if args[0] == ::DEFAULT_ROOM || args[0].starts_with(::WHISPER_SIGIL) => {
+                Input::Invalid(format!("{} is not a room you can join.", args[0]))
+            },
+            1 => Input::Send(Command::Join(args[0].to_string())),
+            _ => usage("/join ROOM"),
+        },
+        "leave" | "part" => match args.len() {
+            0 if room.starts_with(::WHISPER_SIGIL) => Input::Close(room.to_string()),
+            0 if room != ::DEFAULT_ROOM => Input::Send(Command::Leave(room.to_string())),
+            1 if args[0].starts_with(::WHISPER_SIGIL) => Input::Close(args[0].to_string()),
+            1 => Input::Send(Command::Leave(args[0].to_string())),
+            _ => usage("/leave [ROOM]"),
+        },
+        "msg" | "whisper" => {
+            let mut parts = rest.splitn(2, char::is_whitespace);
+            match (parts.next(), parts.next().map(str::trim)) {
+                (Some(to), Some(message)) if !to.is_empty() && !message.is_empty() => {
+                    Input::Send(Command::Whisper(to.to_string(), message.to_string()))
+                },
+                _ => usage("/msg NICK MESSAGE"),
+            }
+        },
+        "me" if room == ::DEFAULT_ROOM => {
+            Input::Invalid(String::from("You are not in a room. /join one first, or see /help."))
+        },
+        "me" => match rest {
+            "" => usage("/me ACTION"),
+            text if room.starts_with(::WHISPER_SIGIL) => {
+                Input::Send(Command::Action(format!("@{}", &room[1..]), text.to_string()))
+            },
+            text => Input::Send(Command::Action(room.to_string(), text.to_string())),
+        },
+        "shout" => match rest {
+            "" => usage("/shout MESSAGE"),
+            message if message.starts_with("/me ") => {
+                Input::Send(Command::Action(String::from("*"), message[4..].trim().to_string()))
+            },
+            message => Input::Send(Command::Shout(message.to_string())),
+        },
+        "list" => match args.len() {
+            0 => Input::Send(Command::List(None)),
+            1 => Input::Send(Command::List(Some(args[0].to_string()))),
+            _ => usage("/list [ROOM]"),
+        },
+        "nick" => match args.len() {
+            1 => Input::Send(Command::Nick(args[0].to_string())),
+            _ => usage("/nick NICK"),
+        },
+        "topic" if room == ::DEFAULT_ROOM || room.starts_with(::WHISPER_SIGIL) => {
+            Input::Invalid(String::from("Only rooms have topics."))
+        },
+        "topic" => match rest {
+            "" => Input::Send(Command::Topic(room.to_string(), None)),
+            topic => Input::Send(Command::Topic(room.to_string(), Some(topic.to_string()))),
+        },
+        "kick" if room == ::DEFAULT_ROOM || room.starts_with(::WHISPER_SIGIL) => {
+            Input::Invalid(String::from("You can only kick people out of rooms."))
+        },
+        "kick" => match args.len() {
+            1 => Input::Send(Command::Kick(room.to_string(), args[0].to_string())),
+            _ => usage("/kick NICK"),
+        },
+        "admin" => match args.len() {
+            1 => Input::Send(Command::Admin(args[0].to_string())),
+            _ => usage("/admin PASSWORD"),
+        },
+        "announce" => match rest {
+            "" => usage("/announce MESSAGE"),
+            message => Input::Send(Command::Announce(message.to_string())),
+        },
+        "away" => match rest {
+            "" => Input::Send(Command::Away(None)),
+            message => Input::Send(Command::Away(Some(message.to_string()))),
+        },
+        "whois" => match args.len() {
+            1 => Input::Send(Command::Whois(args[0].to_string())),
+            _ => usage("/whois NICK"),
+        },
+        "oper" => match args.len() {
+            2 => Input::Send(Command::Oper(args[0].to_string(), args[1].to_string())),
+            _ => usage("/oper NAME PASSWORD"),
+        },
+        "kill" => match args.len() {
+            1 => Input::Send(Command::Kill(args[0].to_string())),
+            _ => usage("/kill NICK"),
+        },
+        "ban" => match args.len() {
+            0 => Input::Send(Command::Ban(None)),
+            1 => Input::Send(Command::Ban(Some(args[0].to_string()))),
+            _ => usage("/ban [RANGE]"),
+        },
+        "unban" => match args.len() {
+            1 => Input::Send(Command::Unban(args[0].to_string())),
+            _ => usage("/unban RANGE"),
+        },
+        "close" => match args.len() {
+            1 => Input::Send(Command::Close(args[0].to_string())),
+            _ => usage("/close ROOM"),
+        },
+        "rename" => match args.len() {
+            2 => Input::Send(Command::Rename(args[0].to_string(), args[1].to_string())),
+            _ => usage("/rename NICK NEWNICK"),
+        },
+        "reload" => Input::Send(Command::Reload),
+        "verify" => match args.len() {
+            1 => Input::Verify(args[0].to_string()),
+            _ => usage("/verify NICK"),
+        },
+        "quit" | "exit" => Input::Quit,
+        "help" => Input::Help,
+        _ => Input::Invalid(format!("Unknown command /{}. See /help.", name)),
+    }
+}
@@ -1,107 +1,305 @@
 extern crate common;
+extern crate srcp_client;
 extern crate chrono;
+extern crate libc;
 extern crate ncurses;
 
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate unicode_width;
+
+mod complete;
+mod config;
+mod editor;
+mod history;
+mod input;
 mod ui;
 mod server;
 
+use std::collections::HashMap;
+use std::time::Instant;
+
+use common::Command;
+use complete::Completion;
+use config::Config;
+use editor::Editor;
+use history::{History, Search};
+use input::Input;
+
 const DEFAULT_ROOM: &str = "server";
+// Marks buffers that hold a whisper conversation rather than a room.
+const WHISPER_SIGIL: char = '@';
 const INPUT_WINDOW_HEIGHT: usize = 3;
 const ROOM_WINDOW_WIDTH: usize = 16;
+const MEMBER_WINDOW_WIDTH: usize = 16;
+
+// Where a window goes: its rows and columns, and the row and column of its
+// top left corner.
+type Placement = (usize, usize, usize, usize);
+
+// Lays out the chat, room, input and, if wanted, member windows for a
+// terminal of the given size: the room list on the left, the chat to its
+// right, the member list on the right and the input along the bottom.
+fn layout(rows: usize, cols: usize, members: bool)
+          -> (Placement, Placement, Placement, Option<Placement>) {
+    // Windows are kept at least big enough for their border.
+    let top = std::cmp::max(rows.saturating_sub(INPUT_WINDOW_HEIGHT), 3);
+    let room_width = std::cmp::min(ROOM_WINDOW_WIDTH, cols);
+    let member_width = if members { MEMBER_WINDOW_WIDTH } else { 0 };
+    let chat_width = std::cmp::max(cols.saturating_sub(room_width + member_width), 3);
+
+    ((top, chat_width, 0, room_width),
+     (top, room_width, 0, 0),
+     (INPUT_WINDOW_HEIGHT, cols, top, 0),
+     if members { Some((top, member_width, 0, room_width + chat_width)) } else { None })
+}
+
+// Lists the rooms, with the current one in reverse video. Rooms with
+// unread messages show how many, in bold if one of them mentions us or is
+// a whisper.
+fn fill_room_window(room_window: ncurses::WINDOW, server: &server::Server, curr: &str) {
+    let width = ui::inner_cols(room_window);
+
+    let entries: Vec<(String, ncurses::attr_t)> = server.get_rooms().into_iter().map(|room| {
+        let (count, highlight) = server.unread(&room);
 
-fn fill_room_window(room_window: ncurses::WINDOW, lines: &[String]) {
-    ui::fill_from_top_down(room_window, lines);
+        let label = match count {
+            0 => ui::fit(&room, width),
+            n => {
+                let count = n.to_string();
+                let name = ui::fit(&room, width.saturating_sub(count.len() + 1));
+                format!("{} {}", name, count)
+            },
+        };
+
+        let mut attr = 0;
+        if room == curr {
+            attr |= ncurses::A_REVERSE();
+        }
+        if highlight {
+            attr |= ncurses::A_BOLD();
+        }
+
+        (label, attr)
+    }).collect();
+
+    ui::fill_from_top_down(room_window, &entries);
+}
+
+// Messages wrapped to the width of the chat window.
+fn chat_rows(chat_window: ncurses::WINDOW, lines: &[String]) -> Vec<String> {
+    ui::wrap(lines, ui::inner_cols(chat_window))
 }
 
-fn fill_chat_window(chat_window: ncurses::WINDOW, lines: &[String]) {
-    ui::fill_from_bottom_up(chat_window, lines);
+fn fill_chat_window(chat_window: ncurses::WINDOW, lines: &[String], bottom: Option<usize>) {
+    ui::fill_from_bottom_up(chat_window, &chat_rows(chat_window, lines), bottom);
 }
 
-fn change_room(room_window: ncurses::WINDOW,
-               curr: &mut String,
-               server: &server::Server,
-               up: bool) -> (String, Vec<String>) {
-    let mut rooms = server.get_rooms();
-    rooms.sort();
+// Moves the scrollback of `room` by a page, or to the top or bottom.
+// `scroll` holds the index of the row shown at the bottom of the chat
+// window for rooms that are scrolled back; the others follow new messages.
+fn scroll_chat(chat_window: ncurses::WINDOW,
+               scroll: &mut HashMap<String, usize>,
+               room: &str,
+               lines: &[String],
+               key: i32) {
+    let page = std::cmp::max(ui::inner_rows(chat_window), 1);
+    let last = chat_rows(chat_window, lines).len().saturating_sub(1);
+    // Scrolling further up than this would leave blank rows at the top.
+    let top = std::cmp::min(page - 1, last);
 
-    let mut index = match rooms.iter().position(|r| r.eq(&curr.clone())) {
-        Some(i) => i,
-        None => 0,
+    let bottom = scroll.get(room).cloned().unwrap_or(last);
+    let bottom = match key {
+        ncurses::KEY_PPAGE => std::cmp::max(bottom.saturating_sub(page), top),
+        ncurses::KEY_NPAGE => bottom + page,
+        ncurses::KEY_SHOME => top,
+        _ => last,
     };
 
+    if bottom >= last {
+        scroll.remove(room);
+    } else {
+        scroll.insert(room.to_string(), bottom);
+    }
+}
+
+// The room above or below `curr` in the room list.
+fn change_room(curr: &str, server: &server::Server, up: bool) -> String {
+    let rooms = server.get_rooms();
+
+    let mut index = rooms.iter().position(|r| r == curr).unwrap_or_default();
+
     let len = rooms.len();
     if len > 0 {
         if up {
-            if index > 0 {
-                index -= 1;
-            }
-        } else {
-            if index < (len - 1) {
-                index += 1;
-            }
+            index = index.saturating_sub(1);
+        } else if index < (len - 1) {
+            index += 1;
         }
     }
-    
-    let new_curr = rooms[index].clone();
-    ui::clear_and_box(room_window);
-    fill_room_window(room_window, &rooms);
-    ncurses::wrefresh(room_window);
 
-    let msgs = server.get_messages(&new_curr).expect("change room");
-
-    (new_curr, msgs)
+    rooms.get(index).cloned().unwrap_or_else(|| String::from(DEFAULT_ROOM))
 }
 
-fn update_room_window(room_win: ncurses::WINDOW, rooms: &[String])
+fn update_room_window(room_win: ncurses::WINDOW, server: &server::Server, curr: &str)
 {
     ui::clear_and_box(room_win);
-    fill_room_window(room_win, rooms);
+    fill_room_window(room_win, server, curr);
     ncurses::wrefresh(room_win);
 }
 
-fn update_chat_room(win: ncurses::WINDOW, messages: &[String]) {
+// Lists the people in a room, with ourselves in bold and those who are
+// away dimmed.
+fn update_member_window(member_win: ncurses::WINDOW, server: &server::Server, room: &str) {
+    let entries: Vec<(String, ncurses::attr_t)> = server.members(room).into_iter()
+        .map(|name| {
+            let mut attr = if server.nick() == Some(name.as_str()) { ncurses::A_BOLD() } else { 0 };
+            if server.is_away(&name) {
+                attr |= ncurses::A_DIM();
+            }
+            (name, attr)
+        })
+        .collect();
+
+    ui::clear_and_box(member_win);
+    ui::fill_from_top_down(member_win, &entries);
+    ncurses::wrefresh(member_win);
+}
+
+// Shows `room` in the chat and member windows and marks it read.
+fn show_room(room_win: ncurses::WINDOW,
+             chat_win: ncurses::WINDOW,
+             member_win: Option<ncurses::WINDOW>,
+             server: &mut server::Server,
+             scroll: &HashMap<String, usize>,
+             room: &str) {
+    server.mark_read(room);
+    update_room_window(room_win, server, room);
+    if let Some(member_win) = member_win {
+        update_member_window(member_win, server, room);
+    }
+
+    let messages = server.get_messages(room).unwrap_or_default();
+    let topic = server.topic(room).unwrap_or_default();
+    update_chat_room(chat_win, &messages, scroll.get(room).cloned(), topic);
+}
+
+// Redraws the chat window, with the room's topic on the top border.
+fn update_chat_room(win: ncurses::WINDOW, messages: &[String], bottom: Option<usize>, topic: &str) {
     ui::clear_and_box(win);
-    fill_chat_window(win, messages);
+    ui::draw_title(win, topic);
+    fill_chat_window(win, messages, bottom);
     ncurses::wrefresh(win);
 
 }
 
+// Handles a key typed while searching the history, showing the newest
+// line that matches on the input line. Typing extends the search, Ctrl-R
+// looks further back, and Ctrl-G or Escape cancel it. Any other key ends
+// the search, leaving the match to be edited, and is handed back for the
+// main loop along with what's left of the search.
+fn search_history(ui: &ui::Ui,
+                  input_win: ncurses::WINDOW,
+                  line: &mut Editor,
+                  history: &History,
+                  mut search: Search) -> (Option<Search>, Option<i32>) {
+    let key = match ui.readkey(input_win) {
+        Some(key) => key,
+        None => return (Some(search), None),
+    };
+
+    let done = match key {
+        ncurses::WchResult::KeyCode(ncurses::KEY_BACKSPACE) => {
+            search.pop(history);
+            None
+        },
+        ncurses::WchResult::KeyCode(key) => Some(Some(key)),
+        ncurses::WchResult::Char(ch) => match std::char::from_u32(ch).unwrap_or('\0') {
+            '\u{12}' => {
+                search.older(history);
+                None
+            },
+            '\u{7f}' | '\u{8}' => {
+                search.pop(history);
+                None
+            },
+            '\u{7}' | '\u{1b}' => {
+                line.set(&search.draft);
+                Some(None)
+            },
+            '\n' | '\r' => Some(Some(ncurses::KEY_ENTER)),
+            ch if !ch.is_control() => {
+                search.push(ch, history);
+                None
+            },
+            _ => Some(None),
+        },
+    };
+
+    if let Some(key) = done {
+        ui::draw_input(input_win, line, "");
+        return (None, key);
+    }
+
+    if let Some(index) = search.found {
+        line.set(history.get(index));
+    }
+    let title = match search.found {
+        Some(_) => format!("search: {}", search.query),
+        None => format!("search (no match): {}", search.query),
+    };
+    ui::draw_input(input_win, line, &title);
+
+    (Some(search), None)
+}
+
 fn main() {
+    let config = match Config::load() {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", config::USAGE);
+            return;
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", config::USAGE);
+            std::process::exit(2);
+        },
+    };
+
+    // Connect before taking over the terminal so that connection and
+    // certificate errors stay readable.
+    let address = config.address();
+    let mut server = match server::Server::new(&address, &config.security()) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", address, e);
+            std::process::exit(1);
+        },
+    };
+    server.login(config.nicks(), config.rooms.clone());
+
     let mut ui = ui::Ui::new();
-    let mut server = server::Server::new("localhost:6667")
-        .expect("failed to connect");
 
     ncurses::noecho();
     ncurses::cbreak();
     ncurses::halfdelay(1);
 
-    let rows = ui.rows();
-    let cols = ui.cols();
-
-    let chat_win = ui.add_window(
-        rows - INPUT_WINDOW_HEIGHT,
-        cols - ROOM_WINDOW_WIDTH,
-        0,
-        ROOM_WINDOW_WIDTH).expect("chat window");
-
-    let room_win = ui.add_window(
-        rows - INPUT_WINDOW_HEIGHT,
-        ROOM_WINDOW_WIDTH,
-        0,
-        0).expect("room window");
-
-    let input_win = ui.add_window(
-        INPUT_WINDOW_HEIGHT,
-        cols,
-        rows - INPUT_WINDOW_HEIGHT,
-        0).expect("input window");
+    let show_members = config.member_list();
+    let (chat, room, input, members) = layout(ui.rows(), ui.cols(), show_members);
+
+    let chat_win = ui.add_window(chat.0, chat.1, chat.2, chat.3).expect("chat window");
+    let room_win = ui.add_window(room.0, room.1, room.2, room.3).expect("room window");
+    let input_win = ui.add_window(input.0, input.1, input.2, input.3).expect("input window");
+    let member_win = members.map(|m| ui.add_window(m.0, m.1, m.2, m.3).expect("member window"));
     
     ncurses::keypad(input_win, true);
     
     let mut curr_room = String::from(DEFAULT_ROOM);
-    let mut rooms = server.get_rooms();
-    fill_room_window(room_win, &rooms);
-    ncurses::wrefresh(room_win);
+    let mut scroll = HashMap::new();
+    show_room(room_win, chat_win, member_win, &mut server, &scroll, &curr_room);
 
     // Input update loop - a single-threaded compromise
     // for a simple client implementation.
@@ -120,67 +318,164 @@ fn main() {
     // waiting, the client will parse the lines and commit
     // them to the appropriate data structures.
 
-    let mut buf = String::new();
+    let mut line = Editor::new();
+    let mut history = History::load();
+    let mut search = None;
+    let mut completion: Option<Completion> = None;
+    let auto_away = config.auto_away();
+    let mut last_typed = Instant::now();
     loop {
-        if buf.len() == 0 {
-            ncurses::wmove(input_win, 1, 1);
-        }
-        
-        match ui.readline(input_win, &mut buf) {
-            Ok(key) => {
-                match key {
-                    ncurses::KEY_ENTER => {
-                        // Dispatch message.
-                        server.send(&buf.clone());
-
-                        // Clean up the input window, clear the contents,
-                        // reset the buffer, and move the input cursor back
-                        // to its initial position.
-                        ncurses::wmove(input_win, 1, 1);
-                        ui::clear_and_box(input_win);
-                        buf = String::new();
-                    },
-                    ncurses::KEY_UP => {
-                        let (new_room, new_msgs) = change_room(
-                            room_win,
-                            &mut curr_room,
-                            &server,
-                            true);
-                        curr_room = new_room;
-
-                        update_chat_room(chat_win, &new_msgs);
-                    },
-                    ncurses::KEY_DOWN => {
-                        let (new_room, new_msgs) = change_room(
-                            room_win,
-                            &mut curr_room,
-                            &server,
-                            false);
-                        curr_room = new_room;
-
-                        update_chat_room(chat_win, &new_msgs);
-                    },
-                    _ => (),
-                }
+        // Put the cursor back in the input window after drawing elsewhere.
+        ncurses::wrefresh(input_win);
+
+        let before = line.text().to_string();
 
+        // None means the read timed out or the key was an editing key.
+        let key = match search.take() {
+            Some(s) => {
+                let (s, key) = search_history(&ui, input_win, &mut line, &history, s);
+                search = s;
+                key
             },
-            Err(e) => {
-                match e.kind() {
-                    // This means a time out has occurred
-                    std::io::ErrorKind::WouldBlock => (),
-                    _ => (),
-                }
+            None => ui.readline(input_win, &mut line),
+        };
+
+        if key.is_some() || line.text() != before {
+            last_typed = Instant::now();
+            server.active();
+        } else if auto_away.is_some_and(|after| last_typed.elapsed() >= after) {
+            server.idle();
+        }
+
+        if let Some(key) = key {
+            match key {
+                ncurses::KEY_ENTER => {
+                    let input = input::parse(line.text(), &curr_room);
+
+                    if let Err(e) = history.add(line.text(), history::saved(&input)) {
+                        server.note(DEFAULT_ROOM, &format!("Cannot save input history: {}", e));
+                    }
+
+                    // Dispatch message.
+                    match input {
+                        Input::Send(command) => server.submit(command),
+                        Input::Verify(name) => server.verify(&name),
+                        Input::Close(buffer) => server.close(&buffer),
+                        Input::Help => {
+                            for line in input::HELP {
+                                server.note(&curr_room, line);
+                            }
+                        },
+                        Input::Quit => {
+                            server.submit(Command::Quit);
+                            break;
+                        },
+                        Input::Invalid(ref reason) if reason.is_empty() => (),
+                        Input::Invalid(reason) => server.note(&curr_room, &reason),
+                    }
+
+                    // Show any notes the command left behind, or go back to
+                    // the server room if the current buffer was closed.
+                    if server.get_messages(&curr_room).is_none() {
+                        scroll.remove(&curr_room);
+                        curr_room = String::from(DEFAULT_ROOM);
+                    }
+                    show_room(room_win, chat_win, member_win, &mut server, &scroll, &curr_room);
+
+                    // Clean up the input window for the next line.
+                    line.clear();
+                    ui::draw_input(input_win, &mut line, "");
+                },
+                ncurses::KEY_UP => {
+                    curr_room = change_room(&curr_room, &server, true);
+                    show_room(room_win, chat_win, member_win, &mut server, &scroll, &curr_room);
+                },
+                ncurses::KEY_DOWN => {
+                    curr_room = change_room(&curr_room, &server, false);
+                    show_room(room_win, chat_win, member_win, &mut server, &scroll, &curr_room);
+                },
+                ui::CTRL_X => {
+                    if let Some(room) = server.next_active(&curr_room) {
+                        curr_room = room;
+                        show_room(room_win, chat_win, member_win, &mut server, &scroll, &curr_room);
+                    }
+                },
+                ncurses::KEY_RESIZE => {
+                    ui.resize();
+
+                    let (chat, room, input, members) = layout(ui.rows(), ui.cols(), show_members);
+                    ui.move_window(chat_win, chat.0, chat.1, chat.2, chat.3);
+                    ui.move_window(room_win, room.0, room.1, room.2, room.3);
+                    ui.move_window(input_win, input.0, input.1, input.2, input.3);
+                    if let (Some(member_win), Some(m)) = (member_win, members) {
+                        ui.move_window(member_win, m.0, m.1, m.2, m.3);
+                    }
+
+                    // Scroll positions count wrapped rows, which change with
+                    // the width, so every room goes back to the bottom.
+                    scroll.clear();
+
+                    show_room(room_win, chat_win, member_win, &mut server, &scroll, &curr_room);
+
+                    ui::draw_input(input_win, &mut line, "");
+                },
+                ui::TAB => {
+                    // Pressing Tab again without editing in between moves on
+                    // to the next candidate.
+                    let cycled = completion.as_mut().is_some_and(|c| c.next(&mut line));
+                    if !cycled {
+                        let nicks: Vec<String> = server.members(&curr_room).into_iter()
+                            .filter(|name| server.nick() != Some(name.as_str()))
+                            .collect();
+                        let rooms = server.get_rooms();
+                        completion = Completion::start(&mut line, &nicks, &rooms);
+                    }
+                    ui::draw_input(input_win, &mut line, "");
+                },
+                ui::CTRL_P => {
+                    if let Some(entry) = history.previous(line.text()) {
+                        line.set(entry);
+                        ui::draw_input(input_win, &mut line, "");
+                    }
+                },
+                ui::CTRL_N => {
+                    if let Some(entry) = history.next() {
+                        line.set(entry);
+                        ui::draw_input(input_win, &mut line, "");
+                    }
+                },
+                ui::CTRL_R => {
+                    search = Some(Search::new(line.text()));
+                    ui::draw_input(input_win, &mut line, "search: ");
+                },
+                ncurses::KEY_PPAGE |
+                ncurses::KEY_NPAGE |
+                ncurses::KEY_SHOME |
+                ncurses::KEY_SEND => {
+                    let messages = server.get_messages(&curr_room).unwrap_or_default();
+                    scroll_chat(chat_win, &mut scroll, &curr_room, &messages, key);
+
+                    let topic = server.topic(&curr_room).unwrap_or_default();
+                    update_chat_room(chat_win, &messages, scroll.get(&curr_room).cloned(), topic);
+                },
+                _ => (),
             }
         }
 
         // Check server for new messages. Updates the chat and room
         // windows.
         if server.update().is_some() {
-            let new_messages = server.get_messages(&curr_room).expect("curr room");
-            update_chat_room(chat_win, &new_messages);
-            
-            rooms = server.get_rooms();
-            update_room_window(room_win, &rooms);
+            // Joining a room switches to it; leaving the current room takes
+            // us back to the server room.
+            if let Some(room) = server.take_joined() {
+                curr_room = room;
+            }
+            if server.get_messages(&curr_room).is_none() {
+                scroll.remove(&curr_room);
+                curr_room = String::from(DEFAULT_ROOM);
+            }
+
+            show_room(room_win, chat_win, member_win, &mut server, &scroll, &curr_room);
         }
     }
 }
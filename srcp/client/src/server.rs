@@ -1,114 +1,818 @@
 use ::std;
 use ::chrono;
-use std::net;
 
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use std::collections::HashMap;
-
-use ::common::{Command, Message};
+use ::common::{self, Command, Message, Notice, StatusCode};
+use ::srcp_client::{Client, Event, Reply, Security, Tag};
+use ::srcp_client::crypto::{self, Keyring, Trust};
+use ::config;
 use chrono::{TimeZone, Timelike};
 
+// What we say when we go away on our own.
+const AUTO_AWAY_MESSAGE: &str = "idle";
+
 pub struct Server {
-    conn: net::TcpStream,
+    client: Client,
+    // Whether the server is still there.
+    connected: bool,
     rooms: HashMap<String, Vec<String>>,
+    // Names still to try if the server says ours is taken, and the rooms
+    // to join once it accepts one.
+    alt_nicks: VecDeque<String>,
+    autojoin: Vec<String>,
+    keyring: Keyring,
+    // Whispers waiting on a GETKEY reply for their peer: outgoing ones
+    // still in plaintext, along with whether each is an action, and
+    // incoming ones still encrypted.
+    outbox: HashMap<String, Vec<(String, bool)>>,
+    inbox: HashMap<String, Vec<Message>>,
+    // The last room the server let us join, until the UI switches to it.
+    joined: Option<String>,
+    // Peers whose key was fetched from the server this session. Keys from
+    // earlier sessions are refreshed before first use so changes show up,
+    // and so are keys of peers who may have reconnected with a new one.
+    fetched: HashSet<String>,
+    // Who is in each room we are in.
+    members: HashMap<String, HashSet<String>>,
+    // Commands the user didn't ask for, whose replies aren't shown.
+    quiet: HashSet<Tag>,
+    // Messages that arrived in each room since the user last looked at it,
+    // and the rooms where one of them mentioned us or was a whisper.
+    unread: HashMap<String, usize>,
+    highlights: HashSet<String>,
+    // The topic of each room we are in that has one.
+    topics: HashMap<String, String>,
+    // Who is away, as far as we know, and whether we went away on our own
+    // because the user stopped typing.
+    away: HashSet<String>,
+    auto_away: bool,
 }
 
 impl Server {
-    pub fn new(addr: &str) -> std::io::Result<Server> {
-        let stream = net::TcpStream::connect(addr)?;
-        stream.set_read_timeout(Some(std::time::Duration::from_millis(85)))
-            .expect("set_read_timeout");
-            
+    pub fn new(addr: &str, security: &Security) -> std::io::Result<Server> {
+        let client = Client::connect(addr, security)?;
+
         let mut r = HashMap::new();
 
         r.insert(String::from(::DEFAULT_ROOM), vec![]);
 
-        Ok( Server { conn: stream, rooms: r, } )
+        Ok( Server {
+            client,
+            connected: true,
+            rooms: r,
+            alt_nicks: VecDeque::new(),
+            autojoin: vec![],
+            keyring: Keyring::load(config::config_dir()?)?,
+            outbox: HashMap::new(),
+            inbox: HashMap::new(),
+            joined: None,
+            fetched: HashSet::new(),
+            members: HashMap::new(),
+            quiet: HashSet::new(),
+            unread: HashMap::new(),
+            highlights: HashSet::new(),
+            topics: HashMap::new(),
+            away: HashSet::new(),
+            auto_away: false,
+        })
+    }
+
+    // Sends a command. Replies to commands the user didn't ask for are not
+    // shown.
+    fn request(&mut self, command: Command, shown: bool) {
+        match self.client.send(command) {
+            Ok(tag) if !shown => {
+                self.quiet.insert(tag);
+            },
+            Ok(_) => (),
+            Err(e) => self.notice(&format!("Cannot send to the server: {}", e)),
+        }
+    }
+
+    // Identifies with the first of `nicks` the server accepts and then joins
+    // `rooms`. Without any nicknames the user has to IDENTIFY by hand.
+    pub fn login(&mut self, nicks: Vec<String>, rooms: Vec<String>) {
+        self.alt_nicks = nicks.into_iter().collect();
+        self.autojoin = rooms;
+
+        if let Some(nick) = self.alt_nicks.pop_front() {
+            self.request(Command::Identify(nick), true);
+        }
+    }
+
+    // Sends a command the user gave. Whispers are encrypted on the way out,
+    // except to services, which are the server itself.
+    pub fn submit(&mut self, command: Command) {
+        match command {
+            // Until we have a name, a new one is simply an IDENTIFY.
+            Command::Nick(name) if self.client.nick().is_none() => {
+                self.request(Command::Identify(name), true);
+            },
+            Command::Whisper(ref to, ref message) if common::is_service(to) => {
+                let me = self.client.nick().unwrap_or_default().to_string();
+                let now = chrono::Utc::now().timestamp() as usize;
+                self.post(&conversation(to), &me, now, message, false);
+
+                self.request(command, true);
+            },
+            Command::Whisper(to, message) => self.whisper(to, message, false),
+            Command::Action(ref to, _) if !to.starts_with('@') => self.request(command, true),
+            Command::Action(to, text) => self.whisper(to[1..].to_string(), text, true),
+            command => self.request(command, true),
+        }
+    }
+
+    // Whispers once we have the peer's key.
+    fn whisper(&mut self, to: String, message: String, action: bool) {
+        self.rooms.entry(conversation(&to)).or_insert(vec![]);
+        self.outbox.entry(to.clone()).or_default().push((message, action));
+
+        if self.fetched.contains(&to) {
+            self.flush_outbox(&to);
+        } else {
+            self.request(Command::GetKey(to), false);
+        }
     }
 
-    pub fn send(&mut self, message: &str) {
-        self.conn.write(message.as_bytes()).expect("write");
-        self.conn.flush().expect("flush");
+    // Asks the server who is in a room without showing the reply.
+    fn refresh_members(&mut self, room: &str) {
+        self.request(Command::List(Some(room.to_string())), false);
     }
 
     pub fn update(&mut self) -> Option<()> {
-        let mut buf = [0; 1024];
-        match self.conn.read(&mut buf) {
-            Ok(0) => {
-
-            },
-            Ok(bytes_read) => {
-                let message = std::str::from_utf8(&buf[0..bytes_read])
-                    .expect("from_utf8");
-                let message = message.trim();
-
-                let all_messages = message.split("\n");
-
-                for msg in all_messages {
-                    match Message::try_new(&msg) {
-                        Ok(m) => {
-                            let mut chathist = self.rooms.entry(m.room)
-                                .or_insert(vec![]);
-
-                            let dt = chrono::Utc.timestamp(m.time as i64, 0);
-                            let human_friendly = format!("[{:02}:{:02}] {}: {}", dt.hour(), dt.minute(), m.sender, m.body);
-                            chathist.push(human_friendly);
-                        },
-                        _ => {
-                            let mut servermsgs = self.rooms.get_mut(::DEFAULT_ROOM)
-                                .expect("no default room");
-                            servermsgs.push(msg.to_string());
-                        },
-                    }
-
-                    self.react(msg);
-                }
-                
+        if !self.connected {
+            return None;
+        }
+
+        let events = match self.client.poll() {
+            Ok(events) => events,
+            Err(e) => {
+                self.connected = false;
+                self.notice(&format!("Disconnected: {}", e));
                 return Some(());
             },
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::WouldBlock => return None,
+        };
+        if events.is_empty() {
+            return None;
+        }
+
+        for event in events {
+            match event {
+                Event::Said(m) => {
+                    self.members.entry(m.room.clone())
+                        .or_default()
+                        .insert(m.sender.clone());
+                    self.count_unread(&m.room, &m.sender, &m.body);
+                    self.post(&m.room, &m.sender, m.time, &m.body, m.is_action());
+                },
+                Event::Whispered(m) => self.on_whisper(m),
+                Event::Notice { room, time, notice } => self.on_notice(&room, time, notice),
+                Event::Reply(reply) => self.on_reply(reply),
+                // The server refuses everything but IDENTIFY until we have
+                // a name, and then hangs up.
+                Event::Unknown(ref line) if line.split_whitespace().nth(1) == Some("UNIDENTIFIED") => {
+                    self.notice("The server hung up because you have no name yet. Restart and pick one with /nick first.");
+                },
+                Event::Unknown(line) => {
+                    let servermsgs = self.rooms.get_mut(::DEFAULT_ROOM)
+                        .expect("no default room");
+                    servermsgs.push(line);
+                },
+            }
+        }
+
+        Some(())
+    }
+
+    // Handles the server's reply to one of our commands. Local state only
+    // changes when the command succeeded; failures are explained in the
+    // buffer they concern.
+    fn on_reply(&mut self, reply: Reply) {
+        let shown = !self.quiet.remove(&reply.tag);
+        let ok = reply.ok();
+        let (code, body) = (reply.code, reply.body);
+
+        match reply.command {
+            Command::List(room) if ok => self.on_list(room, &body, shown),
+            // The server appends the key to the echoed GETKEY.
+            Command::GetKey(name) => match body.split_whitespace().nth(2) {
+                Some(key) if ok => self.on_key(&name, key),
+                _ => self.on_no_key(&name),
+            },
+            // Once we have a name, publish our key so others can
+            // whisper to us.
+            Command::Identify(name) if ok => {
+                self.notice(&format!("You are now known as {}.", name));
+                let key = self.keyring.public_key();
+                self.request(Command::Key(key), false);
+
+                let notice = format!("Your whisper key fingerprint is {}.", self.keyring.fingerprint());
+                self.notice(&notice);
+
+                self.alt_nicks.clear();
+                for room in std::mem::take(&mut self.autojoin) {
+                    self.request(Command::Join(room), true);
+                }
+            },
+            Command::Nick(name) if ok => {
+                self.notice(&format!("You are now known as {}.", name));
+            },
+            Command::Admin(_) if ok => self.notice("You can now /announce."),
+            Command::Oper(name, _) if ok => self.notice(&format!("You are now logged in as operator {}.", name)),
+            // The server appends the bans to the echoed BAN.
+            Command::Ban(None) if ok => {
+                let bans: Vec<&str> = body.split_whitespace().skip(1).collect();
+                match bans.len() {
+                    0 => self.notice("Nobody is banned."),
+                    _ => self.notice(&format!("Banned: {}", bans.join(", "))),
+                }
+            },
+            Command::Ban(Some(range)) if ok && shown => self.notice(&format!("{} is banned.", range)),
+            Command::Unban(range) if ok && shown => self.notice(&format!("{} is no longer banned.", range)),
+            Command::Kill(name) if ok && shown => self.notice(&format!("{} was disconnected.", name)),
+            Command::Close(room) if ok && shown => self.notice(&format!("{} was closed.", room)),
+            Command::Rename(name, new_name) if ok && shown => self.notice(&format!("{} is now known as {}.", name, new_name)),
+            Command::Reload if ok && shown => self.notice("The server reloaded its config."),
+            Command::Join(room) if ok => {
+                self.rooms.entry(room.clone()).or_insert(vec![]);
+                self.refresh_members(&room);
+                self.joined = Some(room);
+            },
+            Command::Whois(name) if ok => self.on_whois(&name, &body),
+            Command::Away(message) if ok => {
+                let me = self.client.nick().unwrap_or_default().to_string();
+                let text = match message {
+                    Some(_) => {
+                        self.away.insert(me);
+                        "You are marked as away."
+                    },
+                    None => {
+                        self.away.remove(&me);
+                        "You are back."
+                    },
+                };
+
+                if shown {
+                    self.notice(text);
+                }
+            },
+            // A topic, if there is one, arrives before the reply.
+            Command::Topic(room, None) if ok && !self.topics.contains_key(&room) => {
+                self.note(&room, &format!("{} has no topic.", room));
+            },
+            _ if ok || !shown => (),
+            Command::Identify(name) if code == StatusCode::UsernameUnavailable => {
+                match self.alt_nicks.pop_front() {
+                    Some(nick) => {
+                        self.notice(&format!("{} is taken, trying {}.", name, nick));
+                        self.request(Command::Identify(nick), true);
+                    },
+                    None => self.notice(&format!("{} is taken. Pick another name with /nick.", name)),
+                }
+            },
+            // The server says what was wrong after the echoed RELOAD.
+            Command::Reload if code == StatusCode::Failed => {
+                let reason = body.split_whitespace().skip(1).collect::<Vec<_>>().join(" ");
+                self.notice(&format!("The server could not reload its config: {}", reason));
+            },
+            command => self.on_failure(code, command),
+        }
+    }
+
+    // Explains why the server turned down a command, in the buffer the
+    // command was about if we have it open.
+    fn on_failure(&mut self, code: StatusCode, command: Command) {
+        let buffer = match command {
+            Command::Whisper(ref to, _) => conversation(to),
+            Command::Action(ref to, _) if to.starts_with('@') => conversation(&to[1..]),
+            Command::Say(ref room, _) |
+            Command::Action(ref room, _) |
+            Command::Leave(ref room) |
+            Command::List(Some(ref room)) |
+            Command::Topic(ref room, _) |
+            Command::Kick(ref room, _) if self.rooms.contains_key(room) => room.clone(),
+            _ => String::from(::DEFAULT_ROOM),
+        };
+
+        let text = match (code, command) {
+            (StatusCode::RoomDoesntExist, Command::Say(room, _)) |
+            (StatusCode::RoomDoesntExist, Command::Action(room, _)) |
+            (StatusCode::RoomDoesntExist, Command::Leave(room)) |
+            (StatusCode::RoomDoesntExist, Command::List(Some(room))) |
+            (StatusCode::RoomDoesntExist, Command::Topic(room, _)) |
+            (StatusCode::RoomDoesntExist, Command::Kick(room, _)) |
+            (StatusCode::RoomDoesntExist, Command::Close(room)) => format!("There is no room called {}.", room),
+            (StatusCode::UserDoesntExist, Command::Whisper(to, _)) => format!("{} is not online; your whisper was not sent.", to),
+            (StatusCode::UserDoesntExist, Command::Action(to, _)) => format!("{} is not online; your whisper was not sent.", &to[1..]),
+            (StatusCode::UserDoesntExist, Command::Kick(room, name)) => format!("{} is not in {}.", name, room),
+            (StatusCode::UserDoesntExist, Command::Whois(name)) |
+            (StatusCode::UserDoesntExist, Command::Kill(name)) |
+            (StatusCode::UserDoesntExist, Command::Rename(name, _)) => format!("{} is not online.", name),
+            (StatusCode::UsernameUnavailable, Command::Identify(name)) |
+            (StatusCode::UsernameUnavailable, Command::Nick(name)) |
+            (StatusCode::UsernameUnavailable, Command::Rename(_, name)) => format!("The nickname {} is taken.", name),
+            (StatusCode::PoorlyFormedCommand, Command::Ban(Some(range))) |
+            (StatusCode::PoorlyFormedCommand, Command::Unban(range)) => {
+                format!("{} is not an address range, like 192.0.2.0/24 or 192.0.2.7.", range)
+            },
+            (StatusCode::AlreadyJoined, Command::Join(room)) => format!("You are already in {}.", room),
+            (StatusCode::PoorlyFormedCommand, Command::Join(room)) => format!("{} is not a valid room name.", room),
+            (StatusCode::PermissionDenied, Command::Say(room, _)) => format!("The server would not let that be said in {}.", room),
+            (StatusCode::PermissionDenied, Command::Action(ref room, _)) if self.rooms.contains_key(room) => {
+                format!("The server would not let that be done in {}.", room)
+            },
+            (StatusCode::PermissionDenied, Command::Topic(room, _)) => format!("Only people in {} can see or change its topic.", room),
+            (StatusCode::PermissionDenied, Command::Kick(room, _)) => format!("Only whoever is in charge of {} can kick people out of it.", room),
+            (StatusCode::PermissionDenied, Command::Admin(_)) => String::from("That is not the admin password."),
+            (StatusCode::PermissionDenied, Command::Announce(_)) => String::from("Only admins and operators can announce; see /admin."),
+            (StatusCode::PermissionDenied, Command::Oper(..)) => String::from("That is not an operator name and password."),
+            (StatusCode::Failed, Command::Admin(_)) |
+            (StatusCode::Failed, Command::Oper(..)) => String::from("Too many password attempts. Wait a moment and try again."),
+            (StatusCode::PermissionDenied, Command::Kill(_)) |
+            (StatusCode::PermissionDenied, Command::Ban(_)) |
+            (StatusCode::PermissionDenied, Command::Unban(_)) |
+            (StatusCode::PermissionDenied, Command::Close(_)) |
+            (StatusCode::PermissionDenied, Command::Rename(..)) |
+            (StatusCode::PermissionDenied, Command::Reload) => String::from("Only operators can do that; see /oper."),
+            (StatusCode::PermissionDenied, Command::Join(_)) |
+            (StatusCode::PermissionDenied, Command::Whisper(..)) |
+            (StatusCode::PermissionDenied, Command::Shout(_)) |
+            (StatusCode::PermissionDenied, Command::Action(..)) => {
+                String::from("This name is registered. Whisper nickserv IDENTIFY <password> first, or pick another name.")
+            },
+            (StatusCode::PoorlyFormedCommand, command) => format!("The server did not understand: {}", command),
+            (code, command) => format!("The server refused {} ({:?}).", command, code),
+        };
+
+        self.note(&buffer, &text);
+    }
+
+    // Describes someone from the `field=value` words after the echoed
+    // WHOIS, skipping any fields this client doesn't know.
+    fn on_whois(&mut self, name: &str, body: &str) {
+        let mut about = vec![];
+
+        for field in body.split_whitespace().skip(2) {
+            let mut parts = field.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("connected"), Some(time)) => if let Ok(time) = time.parse::<i64>() {
+                    let dt = chrono::Utc.timestamp(time, 0);
+                    about.push(format!("connected at {:02}:{:02}", dt.hour(), dt.minute()));
+                },
+                (Some("idle"), Some(secs)) => if let Ok(secs) = secs.parse::<u64>() {
+                    about.push(format!("idle for {}", idle(secs)));
+                },
+                (Some("rooms"), Some("")) => about.push(String::from("shares no rooms with you")),
+                (Some("rooms"), Some(rooms)) => about.push(format!("shares {} with you", rooms.replace(',', ", "))),
+                (Some("address"), Some(address)) => about.push(format!("connected from {}", address)),
                 _ => (),
+            }
+        }
+
+        let text = match about.len() {
+            0 => format!("{} is online.", name),
+            _ => format!("{} is online, {}.", name, about.join("; ")),
+        };
+        self.notice(&text);
+    }
+
+    // Takes the member list out of a reply to LIST, showing the reply if
+    // the user asked for it.
+    fn on_list(&mut self, room: Option<String>, body: &str, shown: bool) {
+        let mut names: Vec<String> = body.split_whitespace().map(String::from).collect();
+        if room.is_some() {
+            for name in names.iter_mut() {
+                if name.ends_with(common::AWAY_SUFFIX) {
+                    name.truncate(name.len() - common::AWAY_SUFFIX.len());
+                    self.away.insert(name.clone());
+                } else {
+                    self.away.remove(name);
+                }
+            }
+        }
+        if let Some(ref room) = room {
+            if self.rooms.contains_key(room) {
+                self.members.insert(room.clone(), names.iter().cloned().collect());
+            }
+        }
+
+        if shown {
+            match room {
+                Some(room) => {
+                    let buffer = if self.rooms.contains_key(&room) { room.clone() } else { String::from(::DEFAULT_ROOM) };
+                    let names: Vec<String> = names.iter()
+                        .map(|name| if self.away.contains(name) { format!("{} (away)", name) } else { name.clone() })
+                        .collect();
+                    self.note(&buffer, &format!("In {}: {}", room, names.join(", ")));
+                },
+                None if names.is_empty() => self.notice("There are no rooms."),
+                None => self.notice(&format!("Rooms: {}", names.join(", "))),
+            }
+        }
+    }
+
+    // Keeps member lists and topics up to date from the server's notices
+    // in rooms, and shows them as something readable.
+    fn on_notice(&mut self, room: &str, time: usize, notice: Notice) {
+        let me = self.client.nick().unwrap_or_default().to_string();
+
+        // The answer to whispering someone who is away comes with "*" in
+        // place of a room, which no room can be called.
+        if let Notice::Away(ref name, ref message) = notice {
+            if room == "*" {
+                self.away.insert(name.clone());
+                self.note(&conversation(name), &format!("{} is away: {}", name, message));
+                return;
+            }
+        }
+
+        // So does being renamed or disconnected by an operator.
+        match notice {
+            Notice::Nick(_, ref new) if room == "*" => {
+                self.notice(&format!("An operator renamed you; you are now known as {}.", new));
+                return;
             },
+            Notice::Kill(ref by, _) if room == "*" => {
+                self.notice(&format!("{} disconnected you from the server.", by));
+                return;
+            },
+            _ => (),
         }
-        None
+
+        // Someone who quit or changed names may come back with a new key.
+        match notice {
+            Notice::Quit(ref name) | Notice::Kill(_, ref name) => {
+                self.fetched.remove(name);
+            },
+            Notice::Nick(ref old, ref new) => {
+                self.fetched.remove(old);
+                self.fetched.remove(new);
+            },
+            _ => (),
+        }
+
+        // Announcements are for everyone rather than any one room.
+        let room = if room == "*" { ::DEFAULT_ROOM } else { room };
+
+        let members = self.members.entry(room.to_string()).or_default();
+
+        let text = match notice {
+            Notice::Joined(name) => {
+                members.insert(name.clone());
+                format!("{} has joined.", name)
+            },
+            Notice::Left(ref name) | Notice::Kick(_, ref name) | Notice::Kill(_, ref name) if *name == me => {
+                self.forget(room);
+                if let Notice::Kick(by, _) = notice {
+                    self.notice(&format!("{} kicked you from {}.", by, room));
+                }
+                return;
+            },
+            Notice::Left(name) => {
+                members.remove(&name);
+                format!("{} has left.", name)
+            },
+            Notice::Quit(name) => {
+                members.remove(&name);
+                format!("{} has quit.", name)
+            },
+            Notice::Nick(old, new) => {
+                if members.remove(&old) {
+                    members.insert(new.clone());
+                }
+                if self.away.remove(&old) {
+                    self.away.insert(new.clone());
+                }
+                format!("{} is now known as {}.", old, new)
+            },
+            Notice::Topic(name, topic) => {
+                self.topics.insert(room.to_string(), topic.clone());
+                format!("{} set the topic: {}", name, topic)
+            },
+            Notice::Kick(by, name) => {
+                members.remove(&name);
+                format!("{} kicked {}.", by, name)
+            },
+            Notice::Away(name, message) => {
+                self.away.insert(name.clone());
+                format!("{} is away: {}", name, message)
+            },
+            Notice::Back(name) => {
+                self.away.remove(&name);
+                format!("{} is back.", name)
+            },
+            Notice::Kill(by, name) => {
+                members.remove(&name);
+                format!("{} disconnected {}.", by, name)
+            },
+            Notice::Announce(name, message) => {
+                self.count_unread(room, "server", &message);
+                format!("Announcement from {}: {}", name, message)
+            },
+        };
+
+        self.post(room, "server", time, &text, false);
+    }
+
+    // Drops everything we know about a room we are no longer in.
+    fn forget(&mut self, room: &str) {
+        self.rooms.remove(room);
+        self.members.remove(room);
+        self.topics.remove(room);
+        self.mark_read(room);
     }
 
-    fn react(&mut self, s: &str) {
-        let pieces: Vec<_> = s.split_whitespace().collect();
-        if pieces.len() < 5 {
+    fn on_whisper(&mut self, m: Message) {
+        self.count_unread(&conversation(&m.sender), &m.sender, "");
+
+        if common::is_service(&m.sender) {
+            self.post(&conversation(&m.sender), &m.sender, m.time, &m.body, m.is_action());
+            return;
+        }
+
+        if !m.body.starts_with(crypto::WHISPER_PREFIX) {
+            let sender = format!("{} [unencrypted]", m.sender);
+            self.post(&conversation(&m.sender), &sender, m.time, &m.body, m.is_action());
             return;
         }
 
-        let c = &pieces[4..pieces.len()].join(" ");
-        let command = Command::new(c);
+        let from = m.sender.clone();
+        self.inbox.entry(from.clone()).or_default().push(m);
 
-        match command {
-            Command::Leave(room) => {
-                self.rooms.remove(&room);
+        if self.fetched.contains(&from) {
+            self.flush_inbox(&from, true);
+        } else {
+            self.request(Command::GetKey(from), false);
+        }
+    }
+
+    fn on_key(&mut self, name: &str, key: &str) {
+        self.fetched.insert(name.to_string());
+
+        match self.keyring.learn(name, key) {
+            Ok(Trust::Changed) => {
+                let notice = format!(
+                    "WARNING: {}'s key has changed! New fingerprint: {}. Whispers to {} are blocked until you /verify {}.",
+                    name, self.keyring.peer_fingerprint(name).unwrap_or_default(), name, name);
+                self.note(&conversation(name), &notice);
+            },
+            Ok(Trust::Unverified) => {
+                let notice = format!(
+                    "{}'s key fingerprint is {} (unverified). Compare it with {} and then /verify {}.",
+                    name, self.keyring.peer_fingerprint(name).unwrap_or_default(), name, name);
+                self.note(&conversation(name), &notice);
             },
-            Command::Quit => {
-                self.rooms = HashMap::new();
+            Ok(Trust::Verified) => (),
+            Err(e) => {
+                self.note(&conversation(name), &format!("Cannot use {}'s key: {}", name, e));
+                self.on_no_key(name);
+                return;
+            },
+        }
+
+        self.flush_outbox(name);
+        self.flush_inbox(name, false);
+    }
+
+    // Whispers cannot be sent without the peer's key, and plaintext is
+    // never sent in their place.
+    fn on_no_key(&mut self, name: &str) {
+        if let Some(pending) = self.outbox.remove(name) {
+            let notice = format!("Cannot whisper {}: no public key available; {} message(s) not sent.", name, pending.len());
+            self.note(&conversation(name), &notice);
+        }
+
+        if let Some(pending) = self.inbox.remove(name) {
+            for m in pending {
+                let sender = format!("{} [undecryptable]", m.sender);
+                self.post(&conversation(name), &sender, m.time, "(no public key available)", false);
             }
-            _ => (),
         }
     }
 
+    fn flush_outbox(&mut self, to: &str) {
+        if self.keyring.trust(to) == Some(Trust::Changed) {
+            let notice = format!("Not whispering {}: their key has changed. /verify {} first.", to, to);
+            self.note(&conversation(to), &notice);
+            self.outbox.remove(to);
+            return;
+        }
+
+        let me = self.client.nick().unwrap_or_default().to_string();
+        let trust = self.keyring.trust(to).map(Trust::label).unwrap_or_default();
+        let sender = format!("{} [{}]", me, trust);
+
+        for (message, action) in self.outbox.remove(to).unwrap_or_default() {
+            match self.keyring.seal(to, &message) {
+                Ok(sealed) => {
+                    let command = match action {
+                        true => Command::Action(format!("@{}", to), sealed),
+                        false => Command::Whisper(to.to_string(), sealed),
+                    };
+                    self.request(command, true);
+
+                    // Show our side of the conversation, too.
+                    let now = chrono::Utc::now().timestamp() as usize;
+                    self.post(&conversation(to), &sender, now, &message, action);
+                },
+                Err(e) => self.note(&conversation(to), &format!("Cannot whisper {}: {}", to, e)),
+            }
+        }
+    }
+
+    // Whispers that don't open with a key fetched earlier may be for a new
+    // one, so with `refetch` they wait for the key to be fetched again.
+    fn flush_inbox(&mut self, from: &str, refetch: bool) {
+        let trust = self.keyring.trust(from).map(Trust::label).unwrap_or_default();
+        let mut unopened = vec![];
+
+        for m in self.inbox.remove(from).unwrap_or_default() {
+            match self.keyring.open(from, &m.body) {
+                Ok(plaintext) => {
+                    let sender = format!("{} [{}]", m.sender, trust);
+                    self.post(&conversation(from), &sender, m.time, &plaintext, m.is_action());
+                },
+                Err(_) if refetch => unopened.push(m),
+                Err(_) => {
+                    let sender = format!("{} [undecryptable]", m.sender);
+                    self.post(&conversation(from), &sender, m.time, "(cannot decrypt whisper)", false);
+                },
+            }
+        }
+
+        if !unopened.is_empty() {
+            self.inbox.insert(from.to_string(), unopened);
+            self.fetched.remove(from);
+            self.request(Command::GetKey(from.to_string()), false);
+        }
+    }
+
+    // Marks a peer's current key as verified after the user has compared
+    // fingerprints with them out of band.
+    pub fn verify(&mut self, name: &str) {
+        let notice = match self.keyring.verify(name) {
+            Ok(true) => format!(
+                "{}'s key {} is now verified.",
+                name, self.keyring.peer_fingerprint(name).unwrap_or_default()),
+            Ok(false) => format!("No key known for {}; whisper them first.", name),
+            Err(e) => format!("Cannot save verification: {}", e),
+        };
+
+        self.notice(&notice);
+    }
+
+    // Counts a message towards a room's unread activity. Whispers and
+    // messages that mention us are highlighted.
+    fn count_unread(&mut self, room: &str, sender: &str, body: &str) {
+        let nick = match self.client.nick() {
+            Some(nick) if nick != sender => nick,
+            _ => return,
+        };
+
+        if room.starts_with(::WHISPER_SIGIL) || common::mentions(body, nick) {
+            self.highlights.insert(room.to_string());
+        }
+        *self.unread.entry(room.to_string()).or_insert(0) += 1;
+    }
+
+    // How many messages are unread in a room, and whether it's highlighted.
+    pub fn unread(&self, room: &str) -> (usize, bool) {
+        (self.unread.get(room).cloned().unwrap_or(0), self.highlights.contains(room))
+    }
+
+    pub fn mark_read(&mut self, room: &str) {
+        self.unread.remove(room);
+        self.highlights.remove(room);
+    }
+
+    // The next room after `curr` with unread messages, trying highlighted
+    // rooms first.
+    pub fn next_active(&self, curr: &str) -> Option<String> {
+        let rooms = self.get_rooms();
+        let at = rooms.iter().position(|r| r == curr).map_or(0, |i| i + 1);
+        let (after, before) = rooms.split_at(std::cmp::min(at, rooms.len()));
+        let order: Vec<&String> = after.iter().chain(before.iter()).collect();
+
+        order.iter()
+            .find(|room| self.highlights.contains(room.as_str()))
+            .or_else(|| order.iter().find(|room| self.unread.contains_key(room.as_str())))
+            .map(|room| room.to_string())
+    }
+
+    // Adds a chat line to a room's history: something the sender said, or
+    // for actions something they did.
+    fn post(&mut self, room: &str, sender: &str, time: usize, body: &str, action: bool) {
+        let chathist = self.rooms.entry(room.to_string())
+            .or_insert(vec![]);
+
+        let dt = chrono::Utc.timestamp(time as i64, 0);
+        let human_friendly = match action {
+            true => format!("[{:02}:{:02}] * {} {}", dt.hour(), dt.minute(), sender, body),
+            false => format!("[{:02}:{:02}] {}: {}", dt.hour(), dt.minute(), sender, body),
+        };
+        chathist.push(human_friendly);
+    }
+
+    // Adds a note from the client itself to the server room.
+    fn notice(&mut self, text: &str) {
+        self.note(::DEFAULT_ROOM, text);
+    }
+
+    // Adds a note from the client itself to a room.
+    pub fn note(&mut self, room: &str, text: &str) {
+        self.rooms.entry(room.to_string())
+            .or_insert(vec![])
+            .push(format!("* {}", text));
+    }
+
+    // Forgets a whisper conversation. It reopens when either side
+    // whispers again.
+    pub fn close(&mut self, buffer: &str) {
+        if buffer.starts_with(::WHISPER_SIGIL) {
+            self.rooms.remove(buffer);
+            self.mark_read(buffer);
+        }
+    }
+
+    // The room most recently joined, if the UI has not asked yet.
+    pub fn take_joined(&mut self) -> Option<String> {
+        self.joined.take()
+    }
+
     pub fn get_messages(&self, room: &str) -> Option<Vec<String>> {
-        if let Some(messages) = self.rooms.get(room) {
-            Some(messages.clone())
+        self.rooms.get(room).cloned()
+    }
+
+    // Everyone in a room, or both sides of a whisper conversation,
+    // sorted by name.
+    pub fn members(&self, room: &str) -> Vec<String> {
+        let mut names: Vec<String> = if room.starts_with(::WHISPER_SIGIL) {
+            self.client.nick().map(String::from).into_iter().chain(Some(room[1..].to_string())).collect()
         } else {
-            None
+            self.members.get(room).map(|names| names.iter().cloned().collect()).unwrap_or_default()
+        };
+        names.sort();
+
+        names
+    }
+
+    pub fn is_away(&self, name: &str) -> bool {
+        self.away.contains(name)
+    }
+
+    // Marks us away once the user has stopped typing for a while, unless
+    // they already said they were.
+    pub fn idle(&mut self) {
+        let me = match self.client.nick() {
+            Some(me) => me.to_string(),
+            None => return,
+        };
+
+        if !self.auto_away && !self.away.contains(&me) {
+            self.auto_away = true;
+            self.request(Command::Away(Some(String::from(AUTO_AWAY_MESSAGE))), false);
         }
     }
 
+    // Takes back an automatic away as soon as the user types again.
+    pub fn active(&mut self) {
+        if self.auto_away {
+            self.auto_away = false;
+            self.request(Command::Away(None), false);
+        }
+    }
+
+    pub fn topic(&self, room: &str) -> Option<&str> {
+        self.topics.get(room).map(String::as_str)
+    }
+
+    pub fn nick(&self) -> Option<&str> {
+        self.client.nick()
+    }
+
     pub fn get_rooms(&self) -> Vec<String> {
-        let r: Vec<_> = self.rooms
+        let mut r: Vec<_> = self.rooms
             .keys()
-            .map(|s| s.clone())
+            .cloned()
             .collect();
+        r.sort();
 
         r
     }
 }
+
+// A length of time in the largest units that fit, like "3h 5m".
+fn idle(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+// The name of the buffer holding the whisper conversation with `nick`.
+// Room names cannot start with the sigil, so the two never clash.
+pub fn conversation(nick: &str) -> String {
+    format!("{}{}", ::WHISPER_SIGIL, nick)
+}
@@ -1,9 +1,20 @@
 use ::std;
 
+use ::libc;
 use ::ncurses;
+use ::unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use editor::Editor;
 
 type Key = i32;
 
+// Control characters that readline leaves to the caller.
+pub const TAB: Key = 0x09;
+pub const CTRL_N: Key = 0x0e;
+pub const CTRL_P: Key = 0x10;
+pub const CTRL_R: Key = 0x12;
+pub const CTRL_X: Key = 0x18;
+
 pub struct Ui {
     rows: usize,
     cols: usize,
@@ -12,6 +23,12 @@ pub struct Ui {
 
 impl Ui {
     pub fn new() -> Ui {
+        // Use the terminal's encoding so that UTF-8 text is drawn as is.
+        // ncurses::setlocale hands C a pointer to a dropped string, so go
+        // to libc directly.
+        unsafe {
+            libc::setlocale(libc::LC_ALL, b"\0".as_ptr() as *const libc::c_char);
+        }
         ncurses::initscr();
         let mut r = 0;
         let mut c = 0;
@@ -43,35 +60,70 @@ impl Ui {
         Ok(w)
     }
 
+    // Reads a key into the line being edited. Keys that aren't editing
+    // keys, like Enter, are returned for the caller to handle; None means
+    // the read timed out or the key was used for editing.
     pub fn readline(&self,
                     window: ncurses::WINDOW,
-                    buf: &mut String)
-                    -> Result<Key, std::io::Error> {
-        let w = window;
-        
-        let ch = ncurses::wgetch(w);
-        if ncurses::ERR != ch {
-            match ch {
-                ncurses::KEY_BACKSPACE => {
-                    buf.pop();
-                },
-                ncurses::KEY_UP => return Ok(ncurses::KEY_UP),
-                ncurses::KEY_DOWN => return Ok(ncurses::KEY_DOWN),
-                _ => {
-                    if let Some(ch) = std::char::from_u32(ch as u32) {
-                        match ch {
-                            '\n' => return Ok(ncurses::KEY_ENTER),
-                            _ => {
-                                buf.push(ch);
-                                ncurses::wechochar(w, ch as u64);
-                            },
-                        }
-                    }
-                },
-            }
+                    line: &mut Editor)
+                    -> Option<Key> {
+        match self.readkey(window)? {
+            ncurses::WchResult::KeyCode(key) => match key {
+                ncurses::KEY_BACKSPACE => line.backspace(),
+                ncurses::KEY_DC => line.delete(),
+                ncurses::KEY_LEFT => line.left(),
+                ncurses::KEY_RIGHT => line.right(),
+                ncurses::KEY_HOME => line.home(),
+                ncurses::KEY_END => line.end(),
+                _ => return Some(key),
+            },
+            ncurses::WchResult::Char(ch) => match std::char::from_u32(ch)? {
+                '\n' | '\r' => return Some(ncurses::KEY_ENTER),
+                // Terminals disagree on what Backspace sends.
+                '\u{7f}' | '\u{8}' => line.backspace(),
+                '\u{1}' => line.home(),
+                '\u{5}' => line.end(),
+                '\u{17}' => line.delete_word(),
+                '\u{15}' => line.kill_to_start(),
+                '\u{b}' => line.kill_to_end(),
+                ch if ch.is_control() => return Some(ch as Key),
+                ch => line.insert(ch),
+            },
         }
-        
-        Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "read timeout"))
+
+        draw_input(window, line, "");
+        None
+    }
+
+    // Reads a single key or character, or None if the read timed out.
+    pub fn readkey(&self, window: ncurses::WINDOW) -> Option<ncurses::WchResult> {
+        ncurses::wget_wch(window)
+    }
+
+    // Picks up the new terminal size after a KEY_RESIZE. The windows have
+    // to be moved into place and redrawn afterwards.
+    pub fn resize(&mut self) {
+        let mut r = 0;
+        let mut c = 0;
+        ncurses::getmaxyx(ncurses::stdscr(), &mut r, &mut c);
+
+        self.rows = r as usize;
+        self.cols = c as usize;
+
+        ncurses::erase();
+        ncurses::refresh();
+    }
+
+    pub fn move_window(
+        &self,
+        window: ncurses::WINDOW,
+        row: usize,
+        col: usize,
+        x: usize,
+        y: usize) {
+        // Resize first so that moving never pushes the window off screen.
+        ncurses::wresize(window, row as i32, col as i32);
+        ncurses::mvwin(window, x as i32, y as i32);
     }
 
     pub fn rows(&self) -> usize {
@@ -97,33 +149,209 @@ pub fn clear_and_box(window: ncurses::WINDOW) {
     ncurses::box_(window, 0, 0);
 }
 
-pub fn fill_from_top_down(window: ncurses::WINDOW, lines: &[String]) {
-    let mut rows = 0;
-    let mut cols = 0;
-    ncurses::getmaxyx(window, &mut rows, &mut cols);
-    rows -= 1;
+// Draws the line being edited and puts the cursor where it belongs. The
+// title, if any, goes on the top border.
+pub fn draw_input(window: ncurses::WINDOW, line: &mut Editor, title: &str) {
+    let (shown, cursor) = line.visible(inner_cols(window));
 
-    let to_print = std::cmp::min(lines.len(), rows as usize - 1);
+    clear_and_box(window);
+    draw_title(window, title);
+    ncurses::mvwaddstr(window, 1, 1, &shown);
+    ncurses::wmove(window, 1, cursor as i32 + 1);
+    ncurses::wrefresh(window);
+}
 
-    ncurses::wmove(window, 1, 1);
-    for i in 0..to_print {
-        ncurses::mvwprintw(window, i as i32 + 1, 1, &lines[i]);
+// Puts a title on the top border, cut short if the window is too narrow.
+pub fn draw_title(window: ncurses::WINDOW, title: &str) {
+    let room = inner_cols(window).saturating_sub(3);
+    if !title.is_empty() && room > 0 {
+        let title = fit(title, std::cmp::min(room, title.width()));
+        ncurses::mvwaddstr(window, 0, 2, &format!(" {} ", title));
+    }
+}
+
+// Fills the window from the top with lines drawn with the given attributes.
+pub fn fill_from_top_down(window: ncurses::WINDOW, lines: &[(String, ncurses::attr_t)]) {
+    let width = inner_cols(window);
+    let to_print = std::cmp::min(lines.len(), inner_rows(window));
+
+    for (i, &(ref line, attr)) in lines.iter().take(to_print).enumerate() {
+        ncurses::wattrset(window, attr);
+        ncurses::mvwaddstr(window, i as i32 + 1, 1, &fit(line, width));
+    }
+    ncurses::wattrset(window, 0);
+}
+
+// Truncates or pads text to exactly `width` columns.
+pub fn fit(text: &str, width: usize) -> String {
+    let mut fitted = String::new();
+    let mut used = 0;
+
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w > width {
+            break;
+        }
+        fitted.push(c);
+        used += w;
     }
+
+    fitted + &" ".repeat(width - used)
 }
 
-pub fn fill_from_bottom_up(window: ncurses::WINDOW, lines: &[String]) {
+// The number of lines a boxed window has room for.
+pub fn inner_rows(window: ncurses::WINDOW) -> usize {
+    std::cmp::max(ncurses::getmaxy(window) - 2, 0) as usize
+}
+
+// The number of columns a boxed window has room for.
+pub fn inner_cols(window: ncurses::WINDOW) -> usize {
+    std::cmp::max(ncurses::getmaxx(window) - 2, 0) as usize
+}
+
+// Breaks lines into rows no wider than `width` columns, measured in
+// display width rather than bytes. Lines are broken between words where
+// possible, and continuation rows are indented to line up with the text.
+pub fn wrap(lines: &[String], width: usize) -> Vec<String> {
+    let mut rows = vec![];
+
+    for line in lines {
+        if width == 0 {
+            rows.push(line.clone());
+        } else {
+            wrap_line(line, width, &mut rows);
+        }
+    }
+
+    rows
+}
+
+fn wrap_line(line: &str, width: usize, rows: &mut Vec<String>) {
+    let indent = std::cmp::min(hanging_indent(line), width / 2);
+    let mut row = String::new();
+    let mut used = 0;
+    // Where the text of the current row starts.
+    let mut start = 0;
+
+    for word in line.split(' ') {
+        let word_width = word.width();
+
+        if used > start {
+            // Words too long for a row of their own are broken where they
+            // are rather than on a fresh row.
+            let fits = used + 1 + word_width <= width;
+            let too_long = word_width > width - indent;
+
+            if fits || too_long && used < width {
+                row.push(' ');
+                used += 1;
+            } else {
+                rows.push(std::mem::replace(&mut row, " ".repeat(indent)));
+                used = indent;
+                start = indent;
+            }
+        }
+
+        for c in word.chars() {
+            let w = c.width().unwrap_or(0);
+            if used + w > width && used > start {
+                rows.push(std::mem::replace(&mut row, " ".repeat(indent)));
+                used = indent;
+                start = indent;
+            }
+            row.push(c);
+            used += w;
+        }
+    }
+
+    rows.push(row);
+}
+
+// Messages look like "[12:34] nick: text", actions like "[12:34] * nick
+// text" and notes like "* text"; the width of everything before the text.
+fn hanging_indent(line: &str) -> usize {
+    let prefix = if line.starts_with("* ") {
+        Some(2)
+    } else if line.starts_with('[') {
+        match line.find("] * ") {
+            Some(i) if !line[..i].contains(' ') => Some(i + 4),
+            _ => line.find(": ").map(|i| i + 2),
+        }
+    } else {
+        None
+    };
+
+    prefix.map(|i| line[..i].width()).unwrap_or(0)
+}
+
+// Fills the window so that line `bottom` ends up on the last row, or the
+// newest line if `bottom` is None. Lines hidden below are counted on the
+// bottom border.
+pub fn fill_from_bottom_up(window: ncurses::WINDOW, lines: &[String], bottom: Option<usize>) {
     let mut rows = 0;
     let mut cols = 0;
     ncurses::getmaxyx(window, &mut rows, &mut cols);
     rows -= 1;
 
-    let to_print = std::cmp::min(lines.len(), rows as usize - 1);
+    let end = match bottom {
+        Some(bottom) => std::cmp::min(bottom + 1, lines.len()),
+        None => lines.len(),
+    };
+    let to_print = std::cmp::min(end, rows as usize - 1);
 
     ncurses::wmove(window, rows - 1, 1);
     for i in 0..to_print {
-        ncurses::mvwprintw(window,
+        ncurses::mvwaddstr(window,
                 rows - i as i32 - 1,
                 1,
-                &lines[lines.len() - i - 1]);
+                &lines[end - i - 1]);
+    }
+
+    if end < lines.len() {
+        ncurses::mvwaddstr(window, rows, 2, &format!(" {} more below ", lines.len() - end));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapped(line: &str, width: usize) -> Vec<String> {
+        wrap(&[line.to_string()], width)
+    }
+
+    #[test]
+    fn short_lines_are_left_alone() {
+        assert_eq!(wrapped("[12:34] bob: hi", 40), vec!["[12:34] bob: hi"]);
+        assert_eq!(wrapped("[12:34] bob: hi", 0), vec!["[12:34] bob: hi"]);
+    }
+
+    #[test]
+    fn breaks_between_words() {
+        assert_eq!(wrapped("aaa bbb ccc", 7), vec!["aaa bbb", "ccc"]);
+    }
+
+    #[test]
+    fn continuations_line_up_with_the_text() {
+        assert_eq!(wrapped("* aaa bbb ccc", 8), vec!["* aaa", "  bbb", "  ccc"]);
+        assert_eq!(wrapped("[12:34] bob: aaaa bbbb", 18), vec!["[12:34] bob: aaaa", "         bbbb"]);
+        assert_eq!(wrapped("[12:34] * bob waves hello", 20), vec!["[12:34] * bob waves", "          hello"]);
+    }
+
+    #[test]
+    fn wide_characters_count_double() {
+        assert_eq!(wrapped("日本語日本語", 6), vec!["日本語", "日本語"]);
+        // A wide character that would straddle the edge moves down.
+        assert_eq!(wrapped("日本語日本語", 5), vec!["日本", "語日", "本語"]);
+    }
+
+    #[test]
+    fn rows_never_exceed_the_width() {
+        let line = "[12:34] 名前: これは とても 長い メッセージ です averyveryverylongword ok";
+        for width in 4..30 {
+            for row in wrapped(line, width) {
+                assert!(row.width() <= width, "{:?} is wider than {}", row, width);
+            }
+        }
     }
 }
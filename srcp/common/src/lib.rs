@@ -1,3 +1,6 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum StatusCode {
     Ok = 0,
     RoomDoesntExist,
@@ -5,8 +8,38 @@ pub enum StatusCode {
     PoorlyFormedCommand,
     UsernameUnavailable,
     AlreadyJoined,
+    NoPublicKey,
+    PermissionDenied,
+    // The server tried and could not, as when RELOAD finds a broken
+    // config. The reply's body says why after the echoed command.
+    Failed,
+}
+
+impl StatusCode {
+    // The status with the given number, as found at the start of a reply.
+    pub fn from_code(code: usize) -> Option<StatusCode> {
+        let codes = [
+            StatusCode::Ok,
+            StatusCode::RoomDoesntExist,
+            StatusCode::UserDoesntExist,
+            StatusCode::PoorlyFormedCommand,
+            StatusCode::UsernameUnavailable,
+            StatusCode::AlreadyJoined,
+            StatusCode::NoPublicKey,
+            StatusCode::PermissionDenied,
+            StatusCode::Failed,
+        ];
+
+        codes.iter().cloned().find(|c| *c as usize == code)
+    }
 }
 
+// Messages from people carry code 0, or this code if they describe
+// something the sender does, to be shown as "* alice waves". Replies from
+// the server carry a StatusCode instead; this code is well clear of them,
+// so an action can never be mistaken for a failed command.
+pub const ACTION_CODE: usize = 100;
+
 pub struct Message {
     pub code: usize,
     pub sender: String,
@@ -16,23 +49,76 @@ pub struct Message {
 }
 
 impl Message {
+    #[allow(clippy::result_unit_err)]
     pub fn try_new(s: &str) -> Result<Self, ()> {
         let pieces: Vec<&str> = s.split_whitespace().collect();
 
-        if pieces.len() < 5 {
+        // The body may be empty, as in the reply to a LIST when there are
+        // no rooms.
+        if pieces.len() < 4 {
             return Err(());
         }
 
         Ok( Self {
-            code: pieces[0].parse::<usize>().unwrap(),
+            code: pieces[0].parse::<usize>().map_err(|_| ())?,
             sender: String::from(pieces[1]),
-            time: pieces[2].parse::<usize>().unwrap(),
+            time: pieces[2].parse::<usize>().map_err(|_| ())?,
             room: String::from(pieces[3]),
             body: pieces[4..pieces.len()].join(" "),
         })
     }
+
+    pub fn is_action(&self) -> bool {
+        self.code == ACTION_CODE
+    }
+}
+
+// A command may start with a tag, any word beginning with '#', which the
+// server puts back at the start of the body of its reply. That way a client
+// with several requests in flight can tell which reply is which:
+//
+//     #12 LIST general
+//     0 server 1528416000 server #12 alice bob
+//
+// Splits a line into its tag, without the '#', and the rest.
+pub fn split_tag(line: &str) -> (Option<&str>, &str) {
+    let line = line.trim_start();
+    if !line.starts_with('#') {
+        return (None, line);
+    }
+
+    let mut parts = line[1..].splitn(2, char::is_whitespace);
+    let tag = parts.next().unwrap_or_default();
+    let rest = parts.next().unwrap_or_default().trim_start();
+
+    (Some(tag), rest)
+}
+
+// Whether `body` mentions `nick` as a word of its own, ignoring case.
+pub fn mentions(body: &str, nick: &str) -> bool {
+    let body = body.to_lowercase();
+    let nick = nick.to_lowercase();
+
+    body.match_indices(&nick).any(|(i, _)| {
+        let before = body[..i].chars().next_back();
+        let after = body[i + nick.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
 }
 
+// Users the server plays itself. Whispering one of them a command, like
+// `WHISPER nickserv HELP`, gets the answer whispered back. Nobody else may
+// take these names.
+pub const SERVICES: &[&str] = &["nickserv", "roomserv"];
+
+pub fn is_service(name: &str) -> bool {
+    SERVICES.contains(&name)
+}
+
+// In the reply to `LIST room`, the names of people who are away end with
+// this. No name may end with it.
+pub const AWAY_SUFFIX: &str = "(away)";
+
 pub enum Command {
     // IDENTIFY nickname
     Identify(String),
@@ -51,6 +137,76 @@ pub enum Command {
     Leave(String),
     // QUIT
     Quit,
+    // KEY public_key
+    //
+    // Publishes the sender's public key for end-to-end encrypted whispers.
+    Key(String),
+    // GETKEY username
+    GetKey(String),
+    // NICK new_nickname
+    Nick(String),
+    // Option 1: TOPIC room_name
+    // Option 2: TOPIC room_name topic goes here!
+    //
+    // Asks for or sets a room's topic.
+    Topic(String, Option<String>),
+    // KICK room_name nickname
+    Kick(String, String),
+    // WHOIS nickname
+    //
+    // Asks about someone. The reply is the command followed by what the
+    // server is willing to tell, as `field=value` words:
+    //
+    //     WHOIS alice connected=1528416000 idle=42 rooms=general,random
+    Whois(String),
+    // Option 1: AWAY
+    // Option 2: AWAY message goes here!
+    //
+    // Says why we are away, or that we are back.
+    Away(Option<String>),
+    // Option 1: ACTION room_name text goes here!
+    // Option 2: ACTION @username text goes here!
+    // Option 3: ACTION * text goes here!
+    //
+    // Like SAY, WHISPER and SHOUT respectively, but describes something the
+    // sender does.
+    Action(String, String),
+    // ADMIN password
+    //
+    // Proves the sender knows the server's admin password.
+    Admin(String),
+    // ANNOUNCE message goes here!
+    //
+    // Tells everyone connected, whatever room they are in. Only for those
+    // who have given the admin password.
+    Announce(String),
+    // OPER name password
+    //
+    // Logs in to one of the operator accounts in the server's config. The
+    // commands after this one are only for operators.
+    Oper(String, String),
+    // KILL nickname
+    //
+    // Disconnects someone.
+    Kill(String),
+    // Option 1: BAN
+    // Option 2: BAN address_range
+    //
+    // Lists the banned ranges, or bans one, like 192.0.2.0/24, and
+    // disconnects everyone in it.
+    Ban(Option<String>),
+    // UNBAN address_range
+    Unban(String),
+    // CLOSE room_name
+    //
+    // Kicks everyone out of a room.
+    Close(String),
+    // RENAME nickname new_nickname
+    Rename(String, String),
+    // RELOAD
+    //
+    // Reads the server's config file again.
+    Reload,
     ParseError,
 }
 
@@ -58,8 +214,21 @@ impl Command {
     pub fn new(message: &str) -> Command {
         let args: Vec<&str> = message.split_whitespace().collect();
 
+        if args.is_empty() {
+            return Command::ParseError;
+        }
+
+        // Commands that take exactly one argument.
+        let single = |f: fn(String) -> Command| {
+            if args.len() == 2 {
+                f(args[1].to_string())
+            } else {
+                Command::ParseError
+            }
+        };
+
         match args[0] {
-            "IDENTIFY" => Command::Identify(args[1].to_string()),
+            "IDENTIFY" => single(Command::Identify),
             "LIST" => {
                 if args.len() > 1 {
                     Command::List(Some(args[1].to_string()))
@@ -67,7 +236,7 @@ impl Command {
                     Command::List(None)
                 }
             },
-            "JOIN" => Command::Join(args[1].to_string()),
+            "JOIN" => single(Command::Join),
             "SAY" => {
                 if args.len() > 2 {
                     Command::Say(args[1].to_string(), args[2..args.len()].join(" "))
@@ -89,17 +258,296 @@ impl Command {
                     Command::ParseError
                 }
             },
-            "LEAVE" => {
-                if args.len() == 2 {
-                    Command::Leave(args[1].to_string())
+            "LEAVE" => single(Command::Leave),
+            "QUIT" => Command::Quit,
+            "KEY" => single(Command::Key),
+            "GETKEY" => single(Command::GetKey),
+            "NICK" => single(Command::Nick),
+            "TOPIC" => {
+                match args.len() {
+                    2 => Command::Topic(args[1].to_string(), None),
+                    n if n > 2 => Command::Topic(args[1].to_string(), Some(args[2..].join(" "))),
+                    _ => Command::ParseError,
+                }
+            },
+            "KICK" => {
+                if args.len() == 3 {
+                    Command::Kick(args[1].to_string(), args[2].to_string())
+                } else {
+                    Command::ParseError
+                }
+            },
+            "WHOIS" => single(Command::Whois),
+            "AWAY" => {
+                if args.len() > 1 {
+                    Command::Away(Some(args[1..].join(" ")))
+                } else {
+                    Command::Away(None)
+                }
+            },
+            "ACTION" => {
+                if args.len() > 2 {
+                    Command::Action(args[1].to_string(), args[2..].join(" "))
+                } else {
+                    Command::ParseError
+                }
+            },
+            "ADMIN" => single(Command::Admin),
+            "OPER" => {
+                if args.len() == 3 {
+                    Command::Oper(args[1].to_string(), args[2].to_string())
+                } else {
+                    Command::ParseError
+                }
+            },
+            "KILL" => single(Command::Kill),
+            "BAN" => {
+                match args.len() {
+                    1 => Command::Ban(None),
+                    2 => Command::Ban(Some(args[1].to_string())),
+                    _ => Command::ParseError,
+                }
+            },
+            "UNBAN" => single(Command::Unban),
+            "CLOSE" => single(Command::Close),
+            "RENAME" => {
+                if args.len() == 3 {
+                    Command::Rename(args[1].to_string(), args[2].to_string())
+                } else {
+                    Command::ParseError
+                }
+            },
+            "RELOAD" => Command::Reload,
+            "ANNOUNCE" => {
+                if args.len() > 1 {
+                    Command::Announce(args[1..].join(" "))
                 } else {
                     Command::ParseError
                 }
             },
-            "QUIT" => Command::Quit,
             _ => Command::ParseError,
         }
 
     }
 }
 
+// Formats a command the way it is sent over the wire, without the
+// trailing newline. A ParseError has no wire form and formats as nothing.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Command::Identify(ref name) => write!(f, "IDENTIFY {}", name),
+            Command::List(Some(ref room)) => write!(f, "LIST {}", room),
+            Command::List(None) => write!(f, "LIST"),
+            Command::Join(ref room) => write!(f, "JOIN {}", room),
+            Command::Say(ref room, ref message) => write!(f, "SAY {} {}", room, message),
+            Command::Whisper(ref to, ref message) => write!(f, "WHISPER {} {}", to, message),
+            Command::Shout(ref message) => write!(f, "SHOUT {}", message),
+            Command::Leave(ref room) => write!(f, "LEAVE {}", room),
+            Command::Quit => write!(f, "QUIT"),
+            Command::Key(ref key) => write!(f, "KEY {}", key),
+            Command::GetKey(ref name) => write!(f, "GETKEY {}", name),
+            Command::Nick(ref name) => write!(f, "NICK {}", name),
+            Command::Topic(ref room, Some(ref topic)) => write!(f, "TOPIC {} {}", room, topic),
+            Command::Topic(ref room, None) => write!(f, "TOPIC {}", room),
+            Command::Kick(ref room, ref name) => write!(f, "KICK {} {}", room, name),
+            Command::Whois(ref name) => write!(f, "WHOIS {}", name),
+            Command::Away(Some(ref message)) => write!(f, "AWAY {}", message),
+            Command::Away(None) => write!(f, "AWAY"),
+            Command::Action(ref to, ref text) => write!(f, "ACTION {} {}", to, text),
+            Command::Admin(ref password) => write!(f, "ADMIN {}", password),
+            Command::Announce(ref message) => write!(f, "ANNOUNCE {}", message),
+            Command::Oper(ref name, ref password) => write!(f, "OPER {} {}", name, password),
+            Command::Kill(ref name) => write!(f, "KILL {}", name),
+            Command::Ban(Some(ref range)) => write!(f, "BAN {}", range),
+            Command::Ban(None) => write!(f, "BAN"),
+            Command::Unban(ref range) => write!(f, "UNBAN {}", range),
+            Command::Close(ref room) => write!(f, "CLOSE {}", room),
+            Command::Rename(ref name, ref new_name) => write!(f, "RENAME {} {}", name, new_name),
+            Command::Reload => write!(f, "RELOAD"),
+            Command::ParseError => Ok(()),
+        }
+    }
+}
+
+// Something that happened in a room. The server sends these into the room
+// as messages from "server" with the notice as the body, for example
+//
+//     0 server 1528416000 general JOINED alice
+//
+// so that clients can keep track of who is in a room.
+#[derive(Clone)]
+pub enum Notice {
+    // JOINED nickname
+    Joined(String),
+    // LEFT nickname
+    Left(String),
+    // QUIT nickname
+    //
+    // Sent to every room the client was in when it disconnected.
+    Quit(String),
+    // NICK old_nickname new_nickname
+    //
+    // Also sent, with "*" in place of a room, to anyone an operator
+    // renames.
+    Nick(String, String),
+    // TOPIC nickname topic goes here!
+    //
+    // Sent when someone sets the topic, and to anyone joining or asking
+    // about a room that has one.
+    Topic(String, String),
+    // KICK nickname kicked_nickname
+    Kick(String, String),
+    // AWAY nickname message goes here!
+    //
+    // Also sent, with "*" in place of a room, to anyone who whispers them.
+    Away(String, String),
+    // BACK nickname
+    Back(String),
+    // ANNOUNCE nickname message goes here!
+    //
+    // Sent to everyone connected, with "*" in place of a room.
+    Announce(String, String),
+    // KILL nickname killed_nickname
+    //
+    // Sent in place of QUIT when an operator disconnects someone, and to
+    // them, with "*" in place of a room.
+    Kill(String, String),
+}
+
+impl Notice {
+    pub fn parse(body: &str) -> Option<Notice> {
+        let args: Vec<&str> = body.split_whitespace().collect();
+
+        match (args.first().cloned(), args.len()) {
+            (Some("JOINED"), 2) => Some(Notice::Joined(args[1].to_string())),
+            (Some("LEFT"), 2) => Some(Notice::Left(args[1].to_string())),
+            (Some("QUIT"), 2) => Some(Notice::Quit(args[1].to_string())),
+            (Some("NICK"), 3) => Some(Notice::Nick(args[1].to_string(), args[2].to_string())),
+            (Some("TOPIC"), n) if n > 2 => Some(Notice::Topic(args[1].to_string(), args[2..].join(" "))),
+            (Some("KICK"), 3) => Some(Notice::Kick(args[1].to_string(), args[2].to_string())),
+            (Some("AWAY"), n) if n > 2 => Some(Notice::Away(args[1].to_string(), args[2..].join(" "))),
+            (Some("BACK"), 2) => Some(Notice::Back(args[1].to_string())),
+            (Some("ANNOUNCE"), n) if n > 2 => Some(Notice::Announce(args[1].to_string(), args[2..].join(" "))),
+            (Some("KILL"), 3) => Some(Notice::Kill(args[1].to_string(), args[2].to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Notice::Joined(ref name) => write!(f, "JOINED {}", name),
+            Notice::Left(ref name) => write!(f, "LEFT {}", name),
+            Notice::Quit(ref name) => write!(f, "QUIT {}", name),
+            Notice::Nick(ref old, ref new) => write!(f, "NICK {} {}", old, new),
+            Notice::Topic(ref name, ref topic) => write!(f, "TOPIC {} {}", name, topic),
+            Notice::Kick(ref by, ref name) => write!(f, "KICK {} {}", by, name),
+            Notice::Away(ref name, ref message) => write!(f, "AWAY {} {}", name, message),
+            Notice::Back(ref name) => write!(f, "BACK {}", name),
+            Notice::Announce(ref name, ref message) => write!(f, "ANNOUNCE {} {}", name, message),
+            Notice::Kill(ref by, ref name) => write!(f, "KILL {} {}", by, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_tag_separates_the_tag() {
+        assert_eq!(split_tag("#12 LIST general"), (Some("12"), "LIST general"));
+        assert_eq!(split_tag("  #a   SAY room hi"), (Some("a"), "SAY room hi"));
+        assert_eq!(split_tag("LIST general"), (None, "LIST general"));
+        assert_eq!(split_tag("#12"), (Some("12"), ""));
+        assert_eq!(split_tag("#"), (Some(""), ""));
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let lines = [
+            "IDENTIFY alice",
+            "LIST",
+            "LIST general",
+            "JOIN general",
+            "SAY general hello there",
+            "WHISPER bob E2E abc=",
+            "SHOUT hello everyone",
+            "LEAVE general",
+            "QUIT",
+            "KEY abc=",
+            "GETKEY bob",
+            "NICK alice2",
+            "TOPIC general",
+            "TOPIC general all about things",
+            "KICK general bob",
+            "WHOIS bob",
+            "AWAY",
+            "AWAY out for lunch",
+            "ACTION general waves",
+            "ACTION @bob waves",
+            "ACTION * waves",
+            "ADMIN secret",
+            "ANNOUNCE restarting soon",
+            "OPER root hunter2",
+            "KILL bob",
+            "BAN",
+            "BAN 192.0.2.0/24",
+            "UNBAN 192.0.2.0/24",
+            "CLOSE general",
+            "RENAME bob robert",
+            "RELOAD",
+        ];
+
+        for line in lines.iter() {
+            assert_eq!(Command::new(line).to_string(), *line);
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_parse_errors() {
+        for line in ["", "JOIN", "JOIN a b", "SAY general", "KICK general", "OPER root", "BAN a b", "FROB"].iter() {
+            assert!(matches!(Command::new(line), Command::ParseError), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn notices_round_trip() {
+        let bodies = [
+            "JOINED alice",
+            "LEFT alice",
+            "QUIT alice",
+            "NICK alice alice2",
+            "TOPIC alice all about things",
+            "KICK alice bob",
+            "AWAY alice out for lunch",
+            "BACK alice",
+            "ANNOUNCE alice restarting soon",
+            "KILL root bob",
+        ];
+
+        for body in bodies.iter() {
+            let notice = Notice::parse(body).expect(body);
+            assert_eq!(notice.to_string(), *body);
+        }
+    }
+
+    #[test]
+    fn actions_are_not_statuses() {
+        assert!(StatusCode::from_code(ACTION_CODE).is_none());
+
+        let action = Message::try_new("100 alice 1528416000 general waves").expect("message");
+        assert!(action.is_action());
+        let failure = Message::try_new("1 server 1528416000 server JOIN nowhere").expect("message");
+        assert!(!failure.is_action());
+    }
+
+    #[test]
+    fn malformed_notices_are_rejected() {
+        for body in ["", "JOINED", "JOINED a b", "TOPIC alice", "hello there"].iter() {
+            assert!(Notice::parse(body).is_none(), "{:?}", body);
+        }
+    }
+}
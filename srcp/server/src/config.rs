@@ -0,0 +1,129 @@
+use ::toml;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:6667";
+
+// Server settings, read from a TOML file given on the command line:
+//
+//     address = "0.0.0.0:6667"
+//     services = "services.txt"
+//     whois = ["connected", "idle", "rooms"]
+//     admin_password = "correct horse battery staple"
+//     oper_log = "oper.log"
+//
+//     [tls]
+//     address = "0.0.0.0:6697"
+//     certificate = "cert.pem"
+//     key = "key.pem"
+//
+//     [[plugins]]
+//     name = "audit"
+//     file = "audit.log"
+//
+//     [[opers]]
+//     name = "root"
+//     password = "hunter2"
+//
+// Every setting is optional; a server started without a config file
+// listens for plaintext connections on the default address only.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_address")]
+    pub address: String,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    // Where nickserv and roomserv keep their registrations.
+    pub services: Option<PathBuf>,
+    // What WHOIS tells about people other than the one asking.
+    #[serde(default = "default_whois")]
+    pub whois: Vec<WhoisField>,
+    // What ADMIN has to be given before ANNOUNCE is allowed. Without one,
+    // nobody can announce.
+    pub admin_password: Option<String>,
+    #[serde(default)]
+    pub opers: Vec<OperConfig>,
+    // Where to record what operators do; the server's output if unset.
+    pub oper_log: Option<PathBuf>,
+}
+
+// An additional listener that only accepts TLS connections. The
+// certificate file may hold a full chain, leaf first; the key file
+// holds the leaf's private key. Both are PEM encoded.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub address: String,
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+// Something WHOIS can tell about someone: when they connected, how long
+// since they last said or whispered anything, the rooms they share with
+// whoever is asking, and the address they connected from.
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhoisField {
+    Connected,
+    Idle,
+    Rooms,
+    Address,
+}
+
+impl WhoisField {
+    pub const ALL: [WhoisField; 4] = [WhoisField::Connected, WhoisField::Idle, WhoisField::Rooms, WhoisField::Address];
+}
+
+// An operator account, for OPER.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperConfig {
+    pub name: String,
+    pub password: String,
+}
+
+// A plugin to turn on, by name, and its own settings, which the plugin
+// checks itself.
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct PluginConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub settings: toml::Table,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+
+        toml::from_str(&contents)
+            .map_err(|e| format!("cannot parse {}: {}", path.display(), e))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            address: default_address(),
+            tls: None,
+            plugins: vec![],
+            services: None,
+            whois: default_whois(),
+            admin_password: None,
+            opers: vec![],
+            oper_log: None,
+        }
+    }
+}
+
+fn default_address() -> String {
+    String::from(DEFAULT_ADDRESS)
+}
+
+// Addresses are kept private unless the config says otherwise.
+fn default_whois() -> Vec<WhoisField> {
+    vec![WhoisField::Connected, WhoisField::Idle, WhoisField::Rooms]
+}
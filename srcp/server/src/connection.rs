@@ -0,0 +1,199 @@
+use ::ring;
+use ::rustls;
+
+use std::fs;
+use std::io;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::io::{Read, Write};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+
+use config::TlsConfig;
+
+// How long a new TLS client may take to complete its handshake before
+// the connection is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The TLS state for one client. The client thread reads from it and the
+// event thread writes to it, so it lives behind a lock.
+struct TlsSession {
+    conn: rustls::ServerConnection,
+    sock: net::TcpStream,
+}
+
+// A connection to a client, either plaintext or over TLS. Handles are
+// cheap to clone and all of them refer to the same underlying socket.
+pub struct Connection {
+    // Remembered so that the client can still be identified after the
+    // socket has been closed.
+    addr: net::SocketAddr,
+    stream: Stream,
+}
+
+enum Stream {
+    Plain(net::TcpStream),
+    Tls(net::TcpStream, Arc<Mutex<TlsSession>>),
+}
+
+impl Connection {
+    pub fn plain(sock: net::TcpStream) -> io::Result<Connection> {
+        Ok(Connection {
+            addr: sock.peer_addr()?,
+            stream: Stream::Plain(sock),
+        })
+    }
+
+    // Performs the server side of the TLS handshake on a freshly accepted
+    // socket.
+    pub fn accept_tls(sock: net::TcpStream, config: Arc<rustls::ServerConfig>) -> io::Result<Connection> {
+        let mut conn = rustls::ServerConnection::new(config).map_err(tls_error)?;
+        let mut raw = sock.try_clone()?;
+
+        raw.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut raw)?;
+        }
+        raw.set_read_timeout(None)?;
+
+        Ok(Connection {
+            addr: raw.peer_addr()?,
+            stream: Stream::Tls(raw, Arc::new(Mutex::new(TlsSession { conn, sock }))),
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        let stream = match self.stream {
+            Stream::Plain(ref s) => Stream::Plain(s.try_clone()?),
+            Stream::Tls(ref s, ref session) => Stream::Tls(s.try_clone()?, session.clone()),
+        };
+
+        Ok(Connection { addr: self.addr, stream })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.addr)
+    }
+
+    pub fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        match self.stream {
+            Stream::Plain(ref s) => s.shutdown(how),
+            Stream::Tls(ref s, ref session) => {
+                if how != net::Shutdown::Read {
+                    let mut session = session.lock().expect("tls lock");
+                    let TlsSession { ref mut conn, ref mut sock } = *session;
+                    conn.send_close_notify();
+                    while conn.wants_write() {
+                        if conn.write_tls(sock).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                s.shutdown(how)
+            },
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref s, ref session) => loop {
+                {
+                    let mut session = session.lock().expect("tls lock");
+                    match session.conn.reader().read(buf) {
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        r => return r,
+                    }
+                }
+
+                // Nothing decrypted yet. Wait for more ciphertext without
+                // holding the lock so the event thread can keep writing to
+                // this client in the meantime.
+                let mut byte = [0; 1];
+                if s.peek(&mut byte)? == 0 {
+                    return Ok(0);
+                }
+
+                let mut session = session.lock().expect("tls lock");
+                let TlsSession { ref mut conn, ref mut sock } = *session;
+                conn.read_tls(sock)?;
+                conn.process_new_packets().map_err(tls_error)?;
+                while conn.wants_write() {
+                    conn.write_tls(sock)?;
+                }
+            },
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stream {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(_, ref session) => {
+                let mut session = session.lock().expect("tls lock");
+                let TlsSession { ref mut conn, ref mut sock } = *session;
+                conn.writer().write_all(buf)?;
+                while conn.wants_write() {
+                    conn.write_tls(sock)?;
+                }
+
+                Ok(buf.len())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stream {
+            Stream::Plain(ref mut s) | Stream::Tls(ref mut s, _) => s.flush(),
+        }
+    }
+}
+
+// Builds the TLS settings shared by every connection on the TLS listener.
+pub fn load_tls_config(tls: &TlsConfig) -> Result<Arc<rustls::ServerConfig>, String> {
+    let certs = load_certificates(tls)?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .map_err(|e| format!("cannot read key {}: {}", tls.key.display(), e))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("invalid certificate or key: {}", e))?;
+
+    Ok(Arc::new(config))
+}
+
+// The SHA-256 fingerprint of the leaf certificate, in the same format
+// `openssl x509 -fingerprint -sha256` prints. Clients can pin this value
+// instead of trusting a certificate authority.
+pub fn fingerprint(tls: &TlsConfig) -> Result<String, String> {
+    let certs = load_certificates(tls)?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, certs[0].as_ref());
+    let hex: Vec<String> = digest.as_ref().iter().map(|b| format!("{:02X}", b)).collect();
+
+    Ok(hex.join(":"))
+}
+
+fn load_certificates(tls: &TlsConfig) -> Result<Vec<CertificateDer<'static>>, String> {
+    let pem = fs::read(&tls.certificate)
+        .map_err(|e| format!("cannot read certificate {}: {}", tls.certificate.display(), e))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("cannot parse certificate {}: {}", tls.certificate.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", tls.certificate.display()));
+    }
+
+    Ok(certs)
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,22 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time;
+
+pub fn unix_time() -> u64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(t) => t.as_secs(),
+        _ => 0,
+    }
+}
+
+// Adds a line to a log file, starting with the Unix time. A file that
+// can't be written to is complained about under `name` rather than
+// stopping the server.
+pub fn append(name: &str, path: &Path, what: &str) {
+    let written = fs::OpenOptions::new().append(true).create(true).open(path)
+        .and_then(|mut file| writeln!(file, "{} {}", unix_time(), what));
+    if let Err(e) = written {
+        eprintln!("{}: cannot write to {}: {}", name, path.display(), e);
+    }
+}
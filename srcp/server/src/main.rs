@@ -1,38 +1,64 @@
 extern crate common;
 
+extern crate ring;
+extern crate rustls;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate threadpool;
+extern crate toml;
 
 use threadpool::ThreadPool;
 
 use std::net;
+use std::path;
+use std::process;
 use std::thread;
 use std::sync;
 use std::io::Read;
 
+use config::Config;
+use connection::Connection;
 use server::Server;
+use services::Services;
 
 use common::Command;
+mod config;
+mod connection;
+mod log;
+mod oper;
+mod plugin;
 mod server;
+mod services;
+mod throttle;
 
 // Max number of supported clients for the server.
 const NCLIENT: usize = 32;
 const MSGSIZE: usize = 1024;
 
 pub struct Event {
-    from: net::TcpStream,
+    from: Connection,
     command: Command,
     raw: String,
+    // The tag the client put in front of the command, to be echoed in the
+    // reply.
+    tag: Option<String>,
 }
 
 // Entry point for client threads. Listens for message from
 // the client, parses it, and sends to the event processing
 // thread for relaying the message.
-fn handle_client(mut stream: net::TcpStream, cmd_queue: sync::mpsc::Sender<Event>) {
+//
+// Commands are terminated by a newline. A single read may
+// carry several commands, or only part of one, so bytes are
+// buffered until a full line has arrived.
+fn handle_client(mut stream: Connection, cmd_queue: sync::mpsc::Sender<Event>) {
     let remote = stream.peer_addr().expect("peer_addr");
 
     println!("{} has connected.", remote);
 
-    loop {
+    let mut pending = Vec::new();
+    'read: loop {
         let mut buf = [0; MSGSIZE];
         match stream.read(&mut buf) {
             Ok(0) => {
@@ -40,23 +66,42 @@ fn handle_client(mut stream: net::TcpStream, cmd_queue: sync::mpsc::Sender<Event
                 break;
             },
             Ok(bytes_read) => {
-                let message = std::str::from_utf8(&buf);
-
-                if message.is_err() {
-                    continue;
+                pending.extend_from_slice(&buf[0..bytes_read]);
+
+                while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = pending.drain(0..end + 1).collect();
+
+                    let message = match std::str::from_utf8(&line) {
+                        Ok(message) => message.trim(),
+                        Err(_) => continue,
+                    };
+
+                    if message.is_empty() {
+                        continue;
+                    }
+
+                    // A tag on its own is as empty as a blank line.
+                    let (tag, message) = common::split_tag(message);
+                    if message.is_empty() {
+                        continue;
+                    }
+                    let event = Event {
+                        from: stream.try_clone().expect("try_clone on client thread"),
+                        command: Command::new(message),
+                        raw: message.to_string(),
+                        tag: tag.map(String::from),
+                    };
+
+                    if let Err(e) = cmd_queue.send(event) {
+                        eprintln!("cannot send client message to event thread: {}", e);
+                        eprintln!("closing connection");
+                        break 'read;
+                    }
                 }
 
-                let message = message.unwrap();
-
-                let event = Event {
-                    from: stream.try_clone().expect("try_clone on client thread"),
-                    command: Command::new(&message[0..bytes_read]),
-                    raw: message[0..bytes_read].trim().to_string(),
-                };
-
-                if let Err(e) = cmd_queue.send(event) {
-                    eprintln!("cannot send client message to event thread: {}", e);
-                    eprintln!("closing connection");
+                // A line that never ends is not a command.
+                if pending.len() > MSGSIZE {
+                    eprintln!("{} sent an overlong command, closing connection", remote);
                     break;
                 }
             },
@@ -68,26 +113,59 @@ fn handle_client(mut stream: net::TcpStream, cmd_queue: sync::mpsc::Sender<Event
         from: stream.try_clone().expect("try_clone on client quit"),
         command: Command::Quit,
         raw: "QUIT".to_string(),
+        tag: None,
     };
 
     if let Err(e) = cmd_queue.send(quit) {
         eprintln!("cannot send client quit to event thread: {}", e);
     }
 
-    match stream.shutdown(net::Shutdown::Both) {
-        _ => println!("Disconnected from {}.", remote),
+    let _ = stream.shutdown(net::Shutdown::Both);
+    println!("Disconnected from {}.", remote);
+}
+
+// Reads the config file named on the command line, if any, and says where
+// it came from so that it can be reloaded.
+fn load_config() -> (Config, Option<path::PathBuf>) {
+    match std::env::args().nth(1) {
+        Some(path) => match Config::load(path::Path::new(&path)) {
+            Ok(config) => (config, Some(path::PathBuf::from(path))),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+        },
+        None => (Config::default(), None),
     }
 }
 
 fn main() {
-    let listener = net::TcpListener::bind("0.0.0.0:6667").expect("bind");
+    let (config, config_path) = load_config();
+    let plugins = match plugin::load(&config.plugins) {
+        Ok(plugins) => plugins,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+    let services = match Services::load(config.services.clone()) {
+        Ok(services) => services,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+
+    let listener = net::TcpListener::bind(&config.address).expect("bind");
 
     let (sender, command_queue) = std::sync::mpsc::channel();
 
+    let server_config = config.clone();
+
     // Event Processing Thread: executes parsed commands
     let events = thread::spawn(move || {
         println!("Event thread online.");
-        let mut server = Server::new();
+        let mut server = Server::new(plugins, services, server_config, config_path);
         for cmd in command_queue {
             server.exec(cmd);
         }
@@ -95,6 +173,43 @@ fn main() {
 
     let pool = ThreadPool::new(NCLIENT);
 
+    // TLS Listener Thread: accepts connections on the TLS address. The
+    // handshake happens on the client's own thread so a slow client
+    // cannot hold up everyone else.
+    if let Some(ref tls) = config.tls {
+        let (tls_config, fingerprint) = match (connection::load_tls_config(tls), connection::fingerprint(tls)) {
+            (Ok(c), Ok(f)) => (c, f),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+        };
+
+        let tls_listener = net::TcpListener::bind(&tls.address).expect("bind tls");
+        println!("Accepting TLS connections on {}.", tls.address);
+        println!("Certificate fingerprint (SHA-256): {}", fingerprint);
+
+        let sender = sender.clone();
+        let pool = pool.clone();
+        thread::spawn(move || {
+            for stream in tls_listener.incoming() {
+                if let Ok(stream) = stream {
+                    println!("Incoming TLS connection!");
+                    let events_queue = sender.clone();
+                    let tls_config = tls_config.clone();
+                    pool.execute(move || {
+                        match Connection::accept_tls(stream, tls_config) {
+                            Ok(conn) => handle_client(conn, events_queue),
+                            Err(e) => eprintln!("TLS handshake failed: {}", e),
+                        }
+                    });
+                } else {
+                    eprintln!("Failed to accept incoming TLS connection.");
+                }
+            }
+        });
+    }
+
     println!("Waiting for connections...");
 
     for stream in listener.incoming() {
@@ -102,7 +217,10 @@ fn main() {
             println!("Incoming connection!");
             let events_queue = sender.clone();
             pool.execute(move || {
-                handle_client(stream, events_queue);
+                match Connection::plain(stream) {
+                    Ok(conn) => handle_client(conn, events_queue),
+                    Err(e) => eprintln!("Failed to set up connection: {}", e),
+                }
             });
         } else {
             eprintln!("Failed to accept incoming connection.");
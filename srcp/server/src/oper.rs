@@ -0,0 +1,145 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+use log;
+
+// A range of addresses banned from the server, written as an address with
+// an optional prefix length, like 192.0.2.0/24 or 2001:db8::/32. A bare
+// address is a range of one.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Range {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Range {
+    pub fn parse(s: &str) -> Option<Range> {
+        let mut parts = s.splitn(2, '/');
+        let address: IpAddr = parts.next()?.parse().ok()?;
+        let max = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max)?,
+            None => max,
+        };
+
+        // Only the bits the prefix covers matter, so 192.0.2.7/24 is the
+        // same range as 192.0.2.0/24.
+        let address = match address {
+            IpAddr::V4(a) => IpAddr::V4((u32::from(a) & mask(prefix, 32) as u32).into()),
+            IpAddr::V6(a) => IpAddr::V6((u128::from(a) & mask(prefix, 128)).into()),
+        };
+
+        Some(Range { address, prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(range), IpAddr::V4(a)) => {
+                let mask = mask(self.prefix, 32) as u32;
+                u32::from(a) & mask == u32::from(range)
+            },
+            (IpAddr::V6(range), IpAddr::V6(a)) => {
+                let mask = mask(self.prefix, 128);
+                u128::from(a) & mask == u128::from(range)
+            },
+            // IPv4 clients of a dual stack listener show up as mapped IPv6
+            // addresses.
+            (IpAddr::V4(_), IpAddr::V6(a)) => a.to_ipv4_mapped().is_some_and(|a| self.contains(IpAddr::V4(a))),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+// The top `prefix` bits of a `bits` wide address.
+fn mask(prefix: u8, bits: u32) -> u128 {
+    match prefix {
+        0 => 0,
+        p => (u128::MAX << (128 - u32::from(p))) >> (128 - bits),
+    }
+}
+
+// Keeps a record of what operators do, one line each, starting with the
+// Unix time. Without a file, the record goes to the server's output.
+pub fn record(file: Option<&Path>, what: &str) {
+    match file {
+        Some(path) => log::append("oper", path, what),
+        None => println!("oper: {} {}", log::unix_time(), what),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> Range {
+        Range::parse(s).unwrap_or_else(|| panic!("{} didn't parse", s))
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn host_bits_are_dropped() {
+        assert_eq!(range("192.0.2.7/24").to_string(), "192.0.2.0/24");
+        assert_eq!(range("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert!(range("192.0.2.7/24") == range("192.0.2.0/24"));
+    }
+
+    #[test]
+    fn bare_addresses_are_a_range_of_one() {
+        assert_eq!(range("192.0.2.7").to_string(), "192.0.2.7/32");
+        assert_eq!(range("2001:db8::1").to_string(), "2001:db8::1/128");
+
+        assert!(range("192.0.2.7/32").contains(ip("192.0.2.7")));
+        assert!(!range("192.0.2.7/32").contains(ip("192.0.2.8")));
+        assert!(range("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!range("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn zero_prefix_covers_the_family() {
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(range("::/0").contains(ip("2001:db8::1")));
+        assert!(!range("::/0").contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn contains_within_the_prefix() {
+        let r = range("192.0.2.7/24");
+        assert!(r.contains(ip("192.0.2.0")));
+        assert!(r.contains(ip("192.0.2.255")));
+        assert!(!r.contains(ip("192.0.3.0")));
+        assert!(!r.contains(ip("2001:db8::1")));
+
+        let r = range("2001:db8::/32");
+        assert!(r.contains(ip("2001:db8:ffff::1")));
+        assert!(!r.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn mapped_ipv4_peers_match_ipv4_ranges() {
+        assert!(range("192.0.2.0/24").contains(ip("::ffff:192.0.2.7")));
+        assert!(!range("192.0.2.0/24").contains(ip("::ffff:192.0.3.7")));
+        assert!(!range("::/0").contains(ip("192.0.2.7")));
+    }
+
+    #[test]
+    fn bad_ranges_are_rejected() {
+        for s in &["192.0.2.0/33", "2001:db8::/129", "192.0.2.0/", "192.0.2.0/-1",
+                   "192.0.2.0/24/8", "192.0.2", "example.com", ""] {
+            assert!(Range::parse(s).is_none(), "{} parsed", s);
+        }
+    }
+}
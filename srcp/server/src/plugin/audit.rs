@@ -0,0 +1,50 @@
+use ::toml;
+
+use std::fs;
+use std::path::PathBuf;
+
+use log;
+use plugin::Plugin;
+
+// Keeps a record of who joined which rooms and when people quit:
+//
+//     [[plugins]]
+//     name = "audit"
+//     file = "audit.log"
+//
+// Each line is the Unix time followed by what happened.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Audit {
+    file: PathBuf,
+}
+
+impl Audit {
+    pub fn new(settings: toml::Value) -> Result<Audit, String> {
+        let audit: Audit = settings.try_into().map_err(|e| e.to_string())?;
+
+        // Find out about a file that can't be written now rather than at
+        // the first join.
+        audit.open().map_err(|e| format!("cannot open {}: {}", audit.file.display(), e))?;
+
+        Ok(audit)
+    }
+
+    fn open(&self) -> ::std::io::Result<fs::File> {
+        fs::OpenOptions::new().append(true).create(true).open(&self.file)
+    }
+
+    fn record(&self, what: &str) {
+        log::append("audit", &self.file, what);
+    }
+}
+
+impl Plugin for Audit {
+    fn after_join(&mut self, nick: &str, room: &str) {
+        self.record(&format!("{} joined {}", nick, room));
+    }
+
+    fn after_quit(&mut self, nick: &str) {
+        self.record(&format!("{} quit", nick));
+    }
+}
@@ -0,0 +1,61 @@
+use ::toml;
+
+use common::StatusCode;
+use config::PluginConfig;
+
+mod audit;
+mod uptime;
+mod wordfilter;
+
+// Extends the server without changing it. Plugins are built in and turned
+// on, in order, by the server's config file. Every hook does nothing by
+// default, so a plugin only implements the ones it cares about.
+//
+// Hooks returning an error veto what was about to happen; the client gets
+// the error's status in the reply to its command.
+pub trait Plugin: Send {
+    // Before `nick` joins `room`.
+    fn before_join(&mut self, _nick: &str, _room: &str) -> Result<(), StatusCode> {
+        Ok(())
+    }
+
+    fn after_join(&mut self, _nick: &str, _room: &str) {
+    }
+
+    // Before `nick` says `body` in `room`, including shouts. The body may
+    // be rewritten; later plugins see the new one.
+    fn before_say(&mut self, _nick: &str, _room: &str, _body: &mut String) -> Result<(), StatusCode> {
+        Ok(())
+    }
+
+    // After `nick` has left every room and disconnected.
+    fn after_quit(&mut self, _nick: &str) {
+    }
+
+    // The commands the plugin adds, in capitals like the built in ones.
+    fn commands(&self) -> Vec<&'static str> {
+        vec![]
+    }
+
+    // Runs one of the plugin's commands for `nick`. `args` are the words
+    // after the command's name. On success the reply's body is the result.
+    fn command(&mut self, _nick: &str, _name: &str, _args: &[&str]) -> Result<String, StatusCode> {
+        Err(StatusCode::PoorlyFormedCommand)
+    }
+}
+
+// Makes the plugins the config file asks for.
+pub fn load(configs: &[PluginConfig]) -> Result<Vec<Box<dyn Plugin>>, String> {
+    configs.iter().map(|config| {
+        let settings = toml::Value::Table(config.settings.clone());
+
+        let plugin: Result<Box<dyn Plugin>, String> = match config.name.as_str() {
+            "audit" => audit::Audit::new(settings).map(|p| Box::new(p) as Box<dyn Plugin>),
+            "uptime" => Ok(Box::new(uptime::Uptime::new())),
+            "wordfilter" => wordfilter::WordFilter::new(settings).map(|p| Box::new(p) as Box<dyn Plugin>),
+            _ => Err(String::from("there is no such plugin")),
+        };
+
+        plugin.map_err(|e| format!("cannot load plugin {}: {}", config.name, e))
+    }).collect()
+}
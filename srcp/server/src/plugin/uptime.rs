@@ -0,0 +1,33 @@
+use std::time::Instant;
+
+use common::StatusCode;
+use plugin::Plugin;
+
+// Adds UPTIME, which answers with how many seconds the server has been
+// running:
+//
+//     [[plugins]]
+//     name = "uptime"
+pub struct Uptime {
+    started: Instant,
+}
+
+impl Uptime {
+    pub fn new() -> Uptime {
+        Uptime { started: Instant::now() }
+    }
+}
+
+impl Plugin for Uptime {
+    fn commands(&self) -> Vec<&'static str> {
+        vec!["UPTIME"]
+    }
+
+    fn command(&mut self, _nick: &str, name: &str, args: &[&str]) -> Result<String, StatusCode> {
+        if !args.is_empty() {
+            return Err(StatusCode::PoorlyFormedCommand);
+        }
+
+        Ok(format!("{} {}", name, self.started.elapsed().as_secs()))
+    }
+}
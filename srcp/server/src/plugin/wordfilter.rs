@@ -0,0 +1,59 @@
+use ::toml;
+
+use common::StatusCode;
+use plugin::Plugin;
+
+// Keeps words out of rooms, either by masking them or by refusing messages
+// that use them:
+//
+//     [[plugins]]
+//     name = "wordfilter"
+//     words = ["darn", "heck"]
+//     block = false
+//
+// Words are matched whole and ignoring case.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WordFilter {
+    words: Vec<String>,
+    #[serde(default)]
+    block: bool,
+}
+
+impl WordFilter {
+    pub fn new(settings: toml::Value) -> Result<WordFilter, String> {
+        let mut filter: WordFilter = settings.try_into().map_err(|e| e.to_string())?;
+        filter.words = filter.words.iter().map(|w| w.to_lowercase()).collect();
+
+        Ok(filter)
+    }
+
+    fn filtered(&self, word: &str) -> bool {
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+        self.words.contains(&word)
+    }
+}
+
+impl Plugin for WordFilter {
+    fn before_say(&mut self, _nick: &str, _room: &str, body: &mut String) -> Result<(), StatusCode> {
+        if !body.split_whitespace().any(|w| self.filtered(w)) {
+            return Ok(());
+        }
+
+        if self.block {
+            return Err(StatusCode::PermissionDenied);
+        }
+
+        let masked: Vec<String> = body.split_whitespace()
+            .map(|w| if self.filtered(w) { mask(w) } else { w.to_string() })
+            .collect();
+        *body = masked.join(" ");
+
+        Ok(())
+    }
+}
+
+// Stars out the letters of a word, leaving any punctuation around it.
+fn mask(word: &str) -> String {
+    word.chars().map(|c| if c.is_alphanumeric() { '*' } else { c }).collect()
+}
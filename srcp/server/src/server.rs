@@ -1,10 +1,19 @@
 use ::net;
+use ::std::mem;
 use ::std::time;
 use ::std::io::Write;
+use ::std::path::PathBuf;
 use ::std::collections::{HashSet, HashMap};
 
 use ::Event;
-use ::common::{Command, StatusCode};
+use ::connection::Connection;
+use ::log::unix_time;
+use ::oper::{self, Range};
+use ::plugin::{self, Plugin};
+use ::services::{self, Services};
+use ::throttle::{self, Throttle};
+use ::config::{Config, WhoisField};
+use ::common::{is_service, Command, Notice, StatusCode, ACTION_CODE, AWAY_SUFFIX};
 
 // Cancels event execution and shuts down the connection
 // if the invoking client has not identified themselves.
@@ -17,13 +26,14 @@ macro_rules! assert_identified {
             let temp_index = $x.iter()
                 .position(|c| c.connection.peer_addr().expect("peer_addr").eq(&$y.from.peer_addr().expect("peer_addr")));
 
-            if temp_index.is_none() {
-                ignore_result($y.from.write(&format!("9 {}\n", "UNIDENTIFIED").as_bytes()));
-                ignore_result($y.from.flush());
-                ignore_result($y.from.shutdown(net::Shutdown::Read));
-                return;
-            } else {
-                temp_index.unwrap()
+            match temp_index {
+                Some(index) => index,
+                None => {
+                    ignore_result($y.from.write_all(format!("9 {}\n", "UNIDENTIFIED").as_bytes()));
+                    ignore_result($y.from.flush());
+                    ignore_result($y.from.shutdown(net::Shutdown::Read));
+                    return;
+                },
             }
         }
     };
@@ -31,8 +41,25 @@ macro_rules! assert_identified {
 
 pub struct Client {
     pub name: String,
-    pub connection: net::TcpStream,
-    pub rooms: HashSet<String>
+    pub connection: Connection,
+    pub rooms: HashSet<String>,
+    // Public key for end-to-end encrypted whispers, as published by the
+    // client. The server only stores and hands it out; it never sees the
+    // whisper contents.
+    pub key: Option<String>,
+    // Whether the client has given nickserv the password for their
+    // current name.
+    pub authenticated: bool,
+    // When the client identified, as a Unix time, and when they last said
+    // or whispered anything.
+    pub connected: u64,
+    pub active: time::Instant,
+    // Why the client is away, if they are.
+    pub away: Option<String>,
+    // Whether the client has given the admin password.
+    pub admin: bool,
+    // The operator account the client logged in to, if any.
+    pub oper: Option<String>,
 }
 
 impl Clone for Client {
@@ -41,6 +68,13 @@ impl Clone for Client {
             name: self.name.clone(),
             connection: self.connection.try_clone().expect("try_clone"),
             rooms: self.rooms.clone(),
+            key: self.key.clone(),
+            authenticated: self.authenticated,
+            connected: self.connected,
+            active: self.active,
+            away: self.away.clone(),
+            admin: self.admin,
+            oper: self.oper.clone(),
         }
     }
 }
@@ -48,21 +82,47 @@ impl Clone for Client {
 pub struct Server {
     pub clients: Vec<Client>,
     pub rooms: HashMap<String, Vec<Client>>,
+    // Each room's topic and who set it. Forgotten when the room empties.
+    pub topics: HashMap<String, (String, String)>,
+    // Consulted, in order, as things happen.
+    pub plugins: Vec<Box<dyn Plugin>>,
+    pub services: Services,
+    // The config the server was started with, or last reloaded, and the
+    // file it came from.
+    pub config: Config,
+    pub config_path: Option<PathBuf>,
+    // Address ranges operators have banned. Forgotten when the server stops.
+    pub bans: Vec<Range>,
+    // When ADMIN and OPER passwords were last tried, so they can't be
+    // guessed any faster than nickserv's.
+    pub passwords: Throttle,
 }
 
 impl Server {
-    pub fn new() -> Server {
+    pub fn new(plugins: Vec<Box<dyn Plugin>>, services: Services, config: Config, config_path: Option<PathBuf>) -> Server {
         Server { 
             clients: Vec::new(),
             rooms: HashMap::new(),
+            topics: HashMap::new(),
+            plugins,
+            services,
+            config,
+            config_path,
+            bans: Vec::new(),
+            passwords: Throttle::new(services::PASSWORD_COOLDOWN),
         }
     }
 
     // Executes a command received by a client thread.
     pub fn exec(&mut self, mut event: Event) {
         let (code, resp) = match event.command {
+            // Banned addresses are turned away before they can do anything.
+            Command::Identify(_) if self.banned(&event.from) => {
+                ignore_result(event.from.shutdown(net::Shutdown::Read));
+                (StatusCode::PermissionDenied, event.raw)
+            },
             Command::Identify(username) => {
-                if self.clients.iter().any(|c| c.name.eq(&username)) {
+                if reserved(&username) || self.clients.iter().any(|c| c.name.eq(&username)) {
                     // Respond with error that it is already taken.
                     (StatusCode::UsernameUnavailable, event.raw)
                 } else {
@@ -70,7 +130,15 @@ impl Server {
                         name: username,
                         connection: event.from.try_clone().expect("try_clone"),
                         rooms: HashSet::new(),
+                        key: None,
+                        authenticated: false,
+                        connected: unix_time(),
+                        active: time::Instant::now(),
+                        away: None,
+                        admin: false,
+                        oper: None,
                     });
+                    self.remind_registered(self.clients.len() - 1);
 
                     (StatusCode::Ok, event.raw)
                 }
@@ -83,30 +151,57 @@ impl Server {
                 // `clients` Vec
                 let index = assert_identified!(self.clients, event);
                 let sender_name = self.clients[index].name.clone();
+                let address = self.clients[index].connection.peer_addr().expect("peer_addr").ip();
+
+                // A registered name can't be used to talk to anyone but the
+                // services, or to publish a key, until its owner has given
+                // nickserv the password.
+                let trusted = self.trusted(&self.clients[index]);
+
+                if matches!(event.command, Command::Say(..) | Command::Whisper(..) | Command::Shout(_) | Command::Action(..)) {
+                    self.clients[index].active = time::Instant::now();
+                }
+
+                // Everything operators do is recorded, along with attempts
+                // at it. Passwords are left out.
+                let is_oper = self.clients[index].oper.is_some();
+                let who = match self.clients[index].oper {
+                    Some(ref oper) => format!("{} ({})", sender_name, oper),
+                    None => sender_name.clone(),
+                };
+                let audited = match event.command {
+                    Command::Admin(_) => Some(String::from("ADMIN")),
+                    Command::Oper(ref name, _) => Some(format!("OPER {}", name)),
+                    Command::Kill(_) | Command::Ban(_) | Command::Unban(_) | Command::Close(_) |
+                    Command::Rename(..) | Command::Reload => Some(event.raw.clone()),
+                    _ => None,
+                };
                 
-                match event.command {
+                let (code, resp) = match event.command {
+                    Command::Join(_) | Command::Say(..) | Command::Shout(_) | Command::Action(..) |
+                    Command::Topic(..) | Command::Kick(..) if !trusted => {
+                        (StatusCode::PermissionDenied, event.raw)
+                    },
+                    Command::Whisper(ref to, _) if !trusted && !is_service(to) => {
+                        (StatusCode::PermissionDenied, event.raw)
+                    },
+                    Command::Key(_) if !trusted => {
+                        (StatusCode::PermissionDenied, event.raw)
+                    },
+                    // Names starting with '@' are left to clients for naming
+                    // whisper conversations, and "*" means everywhere to
+                    // ACTION.
+                    Command::Join(ref room) if room.starts_with('@') || room == "*" => {
+                        (StatusCode::PoorlyFormedCommand, event.raw)
+                    },
                     // Joins a room or creates one if it doesn't yet exist.
                     Command::Join(room) => {
-                        self.clients[index].rooms.insert(room.clone());
-
-                        let list = self.rooms.entry(room.clone()).or_insert(vec![]);
+                        let rc = match self.plugins.iter_mut().try_for_each(|p| p.before_join(&sender_name, &room)) {
+                            Ok(()) => self.on_join(&room, index),
+                            Err(code) => code,
+                        };
 
-                        if list.iter().any(|c| c.name.eq(&sender_name)) {
-                            (StatusCode::AlreadyJoined, event.raw)
-                        } else {
-                            list.push(self.clients[index].clone());
-
-                            // Announce that this client has joined.
-                            let joinmsg = Server::create_message(
-                                0, 
-                                &format!("{} has joined.", self.clients[index].name), 
-                                "server", 
-                                &room
-                            );
-                            Server::say(list.as_mut_slice(), &joinmsg);
-
-                            (StatusCode::Ok, event.raw)
-                        }
+                        (rc, event.raw)
                     },
                     // Lists all rooms or lists the people in that room depending on if
                     // an Option argument is given.
@@ -116,7 +211,14 @@ impl Server {
                             match self.rooms.get(&room) {
                                 // room exists
                                 Some(rm) => {
-                                    let usernames: Vec<String> = rm.iter().map(|c| c.name.clone()).collect();
+                                    // Room lists hold copies of clients, so
+                                    // presence is looked up in the original.
+                                    let usernames: Vec<String> = rm.iter()
+                                        .map(|c| match self.clients.iter().any(|o| o.name.eq(&c.name) && o.away.is_some()) {
+                                            true => format!("{}{}", c.name, AWAY_SUFFIX),
+                                            false => c.name.clone(),
+                                        })
+                                        .collect();
                                     (StatusCode::Ok, usernames.join(" "))
                                 },
                                 None => {
@@ -126,98 +228,643 @@ impl Server {
                             }
                         } else {
                             // User did not provide a room name, so list all the rooms on the server.
-                            let rooms: Vec<String> = self.rooms.keys().map(|k| k.clone()).collect();
+                            let rooms: Vec<String> = self.rooms.keys().cloned().collect();
                             (StatusCode::Ok, rooms.join(" "))
                         }
                     },
                     // Sends a message to a room.
                     Command::Say(room, message) => {
-                        self.on_say(&room, &sender_name, &message);
+                        let rc = match self.on_say(&room, &sender_name, &message, 0) {
+                            Ok(()) => StatusCode::Ok,
+                            Err(code) => code,
+                        };
+
+                        (rc, event.raw)
+                    },
+                    // Services answer whispers themselves, by whispering back.
+                    Command::Whisper(ref to, ref message) if is_service(to) => {
+                        let client = &mut self.clients[index];
+                        let answer = match to.as_str() {
+                            "nickserv" => self.services.nickserv(&sender_name, address, &mut client.authenticated, message),
+                            _ => self.services.roomserv(&sender_name, client.authenticated, &self.rooms, message),
+                        };
+
+                        let answer = Server::create_message(0, &answer, to, &sender_name);
+                        Server::say(&mut [self.clients[index].clone()], &answer);
 
                         (StatusCode::Ok, event.raw)
                     },
                     // Sends a private message to a connected client.
                     Command::Whisper(to, message) => {
-                        let rc = match self.clients.iter().position(|c| c.name.eq(&to)) {
-                            Some(index) => {
-                                let recipient = self.clients[index].clone();
-                                let message = Server::create_message(0, &message, &sender_name, &to);
-                                Server::say(&mut[recipient], &message);
-
-                                StatusCode::Ok
-                            },
-                            None => StatusCode::UserDoesntExist, 
-                        };
+                        let rc = self.on_whisper(index, &to, &message, 0);
                         
                         (rc, event.raw)
                     },
-                    // Broadcasts a message to all rooms.
+                    // Broadcasts a message to all the sender's rooms.
                     Command::Shout(message) => {
-                        let rooms: Vec<_> = self.rooms.iter().map(|(r, _)| r.clone()).collect();
-
-                        for room in rooms {
-                            self.on_say(&room, &sender_name, &message);
-                        }
+                        self.on_shout(index, &message, 0);
 
                         (StatusCode::Ok, event.raw)
                     },
+                    // Describes something the sender does, in a room, to
+                    // someone, or everywhere.
+                    Command::Action(to, text) => {
+                        let rc = if to == "*" {
+                            self.on_shout(index, &text, ACTION_CODE);
+                            StatusCode::Ok
+                        } else if let Some(nick) = to.strip_prefix('@') {
+                            self.on_whisper(index, nick, &text, ACTION_CODE)
+                        } else {
+                            match self.on_say(&to, &sender_name, &text, ACTION_CODE) {
+                                Ok(()) => StatusCode::Ok,
+                                Err(code) => code,
+                            }
+                        };
+
+                        (rc, event.raw)
+                    },
                     // Leaves a room.
                     Command::Leave(room) => {
-                        self.on_leave(&room, &sender_name, index);
+                        self.on_leave(&room, index, Notice::Left(sender_name.clone()));
 
                         (StatusCode::Ok, event.raw)
                     },
                     // Disconnects from the server; as a consequence, leaves all
                     // rooms, too.
                     Command::Quit => {
-                        let client = self.clients[index].clone();
+                        self.disconnect(index, Notice::Quit(sender_name.clone()));
 
-                        // unsubscribe them from each room they belong to.
-                        let subscribed: Vec<_> = client.rooms.iter().map(|r| r.clone()).collect();
-                        for room in subscribed {
-                            self.on_leave(&room, &sender_name, index);
+                        (StatusCode::Ok, event.raw)
+                    },
+                    // Changes the sender's name and tells everyone who shares a
+                    // room with them.
+                    Command::Nick(new_name) => {
+                        let rc = self.rename(index, &new_name);
+
+                        (rc, event.raw)
+                    },
+                    // Marks the sender as away, or back, and tells everyone who
+                    // shares a room with them.
+                    Command::Away(message) => {
+                        let notice = match message {
+                            Some(ref message) => Some(Notice::Away(sender_name.clone(), message.clone())),
+                            None if self.clients[index].away.is_some() => Some(Notice::Back(sender_name.clone())),
+                            None => None,
+                        };
+
+                        self.clients[index].away = message;
+                        if let Some(notice) = notice {
+                            self.announce(index, notice);
+                        }
+
+                        (StatusCode::Ok, event.raw)
+                    },
+                    // Publishes (or replaces) the sender's public key.
+                    Command::Key(key) => {
+                        self.clients[index].key = Some(key);
+
+                        (StatusCode::Ok, event.raw)
+                    },
+                    // Looks up another client's public key. The reply is the
+                    // command followed by the key.
+                    Command::GetKey(username) => {
+                        match self.clients.iter().find(|c| c.name.eq(&username)) {
+                            // A key published before taking a registered name
+                            // isn't its owner's until they identify.
+                            Some(client) if !self.trusted(client) => (StatusCode::NoPublicKey, event.raw),
+                            Some(&Client { key: Some(ref key), .. }) => (StatusCode::Ok, format!("{} {}", event.raw, key)),
+                            Some(_) => (StatusCode::NoPublicKey, event.raw),
+                            None => (StatusCode::UserDoesntExist, event.raw),
                         }
+                    },
+                    // Sets a room's topic, or asks what it is. Only members of
+                    // the room may do either.
+                    Command::Topic(room, topic) => {
+                        let rc = match self.rooms.get_mut(&room) {
+                            None => StatusCode::RoomDoesntExist,
+                            Some(ref members) if !members.iter().any(|c| c.name.eq(&sender_name)) => StatusCode::PermissionDenied,
+                            Some(members) => {
+                                match topic {
+                                    Some(topic) => {
+                                        let notice = Notice::Topic(sender_name.clone(), topic.clone());
+                                        let message = Server::create_message(0, &notice.to_string(), "server", &room);
+                                        Server::say(members.as_mut_slice(), &message);
+
+                                        self.topics.insert(room, (sender_name, topic));
+                                    },
+                                    None => self.tell_topic(&room, index),
+                                }
+
+                                StatusCode::Ok
+                            },
+                        };
+
+                        (rc, event.raw)
+                    },
+                    // Removes someone from a room. Only whoever is in charge of
+                    // the room may.
+                    Command::Kick(room, name) => {
+                        let rc = match self.rooms.get(&room) {
+                            None => StatusCode::RoomDoesntExist,
+                            Some(_) if !self.in_charge(&room, index) => StatusCode::PermissionDenied,
+                            Some(members) if !members.iter().any(|c| c.name.eq(&name)) => StatusCode::UserDoesntExist,
+                            Some(_) => {
+                                let kicked = self.clients.iter().position(|c| c.name.eq(&name));
+                                if let Some(kicked) = kicked {
+                                    self.on_leave(&room, kicked, Notice::Kick(sender_name.clone(), name));
+                                }
 
-                        ignore_result(client.connection.shutdown(net::Shutdown::Both));
+                                StatusCode::Ok
+                            },
+                        };
 
-                        // remove from list of clients
-                        self.clients.remove(index);
+                        (rc, event.raw)
+                    },
+                    // Lets the sender announce things, if they know the admin
+                    // password.
+                    // Replies without the password, like OPER.
+                    Command::Admin(_) if !self.passwords.attempt(&[throttle::address_key(address)]) => {
+                        (StatusCode::Failed, String::from("ADMIN"))
+                    },
+                    Command::Admin(password) => {
+                        let rc = match self.config.admin_password {
+                            Some(ref admin_password) if *admin_password == password => {
+                                self.clients[index].admin = true;
+                                StatusCode::Ok
+                            },
+                            _ => StatusCode::PermissionDenied,
+                        };
+
+                        (rc, String::from("ADMIN"))
+                    },
+                    // Tells everyone connected, whether or not they are in
+                    // any rooms.
+                    Command::Announce(_) if !self.clients[index].admin && !is_oper => {
+                        (StatusCode::PermissionDenied, event.raw)
+                    },
+                    Command::Announce(message) => {
+                        let notice = Notice::Announce(sender_name.clone(), message).to_string();
+                        let message = Server::create_message(0, &notice, "server", "*");
+                        Server::say(self.clients.as_mut_slice(), &message);
 
                         (StatusCode::Ok, event.raw)
                     },
+                    Command::Oper(ref name, _) if !self.passwords.attempt(&[throttle::address_key(address), format!("oper {}", name)]) => {
+                        (StatusCode::Failed, format!("OPER {}", name))
+                    },
+                    // Logs in to an operator account from the config.
+                    Command::Oper(name, password) => {
+                        let resp = format!("OPER {}", name);
+                        let rc = match self.config.opers.iter().find(|o| o.name == name && o.password == password) {
+                            Some(_) => {
+                                self.clients[index].oper = Some(name);
+                                StatusCode::Ok
+                            },
+                            None => StatusCode::PermissionDenied,
+                        };
+
+                        (rc, resp)
+                    },
+                    Command::Kill(_) | Command::Ban(_) | Command::Unban(_) | Command::Close(_) |
+                    Command::Rename(..) | Command::Reload if !is_oper => {
+                        (StatusCode::PermissionDenied, event.raw)
+                    },
+                    // Disconnects someone.
+                    Command::Kill(name) => {
+                        let rc = match self.clients.iter().position(|c| c.name.eq(&name)) {
+                            Some(killed) => {
+                                self.kill(killed, &sender_name);
+                                StatusCode::Ok
+                            },
+                            None => StatusCode::UserDoesntExist,
+                        };
+
+                        (rc, event.raw)
+                    },
+                    // Lists the bans, after the echoed command.
+                    Command::Ban(None) => {
+                        let mut resp = event.raw;
+                        for range in self.bans.iter() {
+                            resp.push_str(&format!(" {}", range));
+                        }
+
+                        (StatusCode::Ok, resp)
+                    },
+                    // Bans a range of addresses, and disconnects everyone
+                    // already connected from it but the operator.
+                    Command::Ban(Some(range)) => {
+                        let rc = match Range::parse(&range) {
+                            Some(range) => {
+                                if !self.bans.contains(&range) {
+                                    self.bans.push(range);
+                                }
+
+                                let in_range = |c: &Client| c.name != sender_name && c.connection.peer_addr().is_ok_and(|a| range.contains(a.ip()));
+                                while let Some(banned) = self.clients.iter().position(&in_range) {
+                                    self.kill(banned, &sender_name);
+                                }
+
+                                StatusCode::Ok
+                            },
+                            None => StatusCode::PoorlyFormedCommand,
+                        };
+
+                        (rc, event.raw)
+                    },
+                    Command::Unban(range) => {
+                        let rc = match Range::parse(&range) {
+                            Some(range) => {
+                                self.bans.retain(|r| *r != range);
+                                StatusCode::Ok
+                            },
+                            None => StatusCode::PoorlyFormedCommand,
+                        };
+
+                        (rc, event.raw)
+                    },
+                    // Kicks everyone out of a room, which goes away once it
+                    // is empty.
+                    Command::Close(room) => {
+                        let rc = match self.rooms.get(&room) {
+                            Some(members) => {
+                                let names: Vec<String> = members.iter().map(|c| c.name.clone()).collect();
+                                for name in names {
+                                    if let Some(kicked) = self.clients.iter().position(|c| c.name.eq(&name)) {
+                                        self.on_leave(&room, kicked, Notice::Kick(sender_name.clone(), name));
+                                    }
+                                }
+
+                                StatusCode::Ok
+                            },
+                            None => StatusCode::RoomDoesntExist,
+                        };
+
+                        (rc, event.raw)
+                    },
+                    // Changes someone else's name. They are told even if
+                    // they share no rooms with anyone.
+                    Command::Rename(name, new_name) => {
+                        let rc = match self.clients.iter().position(|c| c.name.eq(&name)) {
+                            Some(renamed) => {
+                                let rc = self.rename(renamed, &new_name);
+                                if rc == StatusCode::Ok {
+                                    let notice = Notice::Nick(name.clone(), new_name.clone()).to_string();
+                                    let message = Server::create_message(0, &notice, "server", "*");
+                                    Server::say(&mut [self.clients[renamed].clone()], &message);
+                                }
+
+                                rc
+                            },
+                            None => StatusCode::UserDoesntExist,
+                        };
+
+                        (rc, event.raw)
+                    },
+                    // Reads the config file again. On an error the old config
+                    // stays, and the reply says what was wrong, on one line.
+                    Command::Reload => {
+                        match self.reload() {
+                            Ok(()) => (StatusCode::Ok, event.raw),
+                            Err(e) => {
+                                let e: Vec<&str> = e.split_whitespace().collect();
+                                (StatusCode::Failed, format!("{} {}", event.raw, e.join(" ")))
+                            },
+                        }
+                    },
+                    // Tells what there is to know about someone, or as much of
+                    // it as the config allows. People asking about themselves
+                    // are told everything.
+                    Command::Whois(name) => {
+                        match self.clients.iter().find(|c| c.name.eq(&name)) {
+                            Some(client) => {
+                                let asker = &self.clients[index];
+                                let mut resp = event.raw;
+
+                                let fields = WhoisField::ALL.iter()
+                                    .filter(|f| client.name == asker.name || asker.oper.is_some() || self.config.whois.contains(f));
+                                for field in fields {
+                                    match *field {
+                                        WhoisField::Connected => resp.push_str(&format!(" connected={}", client.connected)),
+                                        WhoisField::Idle => resp.push_str(&format!(" idle={}", client.active.elapsed().as_secs())),
+                                        WhoisField::Rooms => {
+                                            let mut shared: Vec<_> = client.rooms.intersection(&asker.rooms).cloned().collect();
+                                            shared.sort();
+                                            resp.push_str(&format!(" rooms={}", shared.join(",")));
+                                        },
+                                        WhoisField::Address => {
+                                            if let Ok(address) = client.connection.peer_addr() {
+                                                resp.push_str(&format!(" address={}", address));
+                                            }
+                                        },
+                                    }
+                                }
+
+                                (StatusCode::Ok, resp)
+                            },
+                            None => (StatusCode::UserDoesntExist, event.raw),
+                        }
+                    },
+                    // Commands added by plugins.
+                    Command::ParseError => {
+                        let words: Vec<&str> = event.raw.split_whitespace().collect();
+                        let plugin = words.first().and_then(|name| {
+                            self.plugins.iter_mut().find(|p| p.commands().contains(name)).map(|p| (p, *name))
+                        });
+
+                        match plugin.map(|(p, name)| p.command(&sender_name, name, &words[1..])) {
+                            Some(Ok(result)) => (StatusCode::Ok, result),
+                            Some(Err(code)) => (code, event.raw),
+                            None => (StatusCode::PoorlyFormedCommand, event.raw),
+                        }
+                    },
                     _ => (StatusCode::PoorlyFormedCommand, event.raw),
+                };
+
+                if let Some(what) = audited {
+                    oper::record(self.config.oper_log.as_deref(), &format!("{} {} {:?}", who, what, code));
                 }
+
+                (code, resp)
             }
         };
 
-        // Echo the command that was just processed back to the client.
+        // Echo the command that was just processed back to the client,
+        // behind its tag if it had one.
+        let resp = match event.tag {
+            Some(tag) => format!("#{} {}", tag, resp),
+            None => resp,
+        };
         let reply = Server::create_message(code as usize, &resp, "server", "server");
 
         Server::say(
             &mut [Client { 
                 name: String::from("repl"), 
                 connection: event.from.try_clone().expect("try_clone"), 
-                rooms: HashSet::new()}
+                rooms: HashSet::new(),
+                key: None,
+                authenticated: false,
+                connected: 0,
+                active: time::Instant::now(),
+                away: None,
+                admin: false,
+                oper: None}
                 ], 
                 &reply
         );
     }
 
-    fn on_say(&mut self, room: &str, user: &str, message: &str) {
+    // Adds the client at `index` to a room, creating it if need be.
+    fn on_join(&mut self, room: &str, index: usize) -> StatusCode {
+        let name = self.clients[index].name.clone();
+        self.clients[index].rooms.insert(room.to_string());
+
+        let list = self.rooms.entry(room.to_string()).or_insert(vec![]);
+
+        if list.iter().any(|c| c.name.eq(&name)) {
+            return StatusCode::AlreadyJoined;
+        }
+
+        list.push(self.clients[index].clone());
+
+        // Announce that this client has joined.
+        let joinmsg = Server::create_message(0, &Notice::Joined(name.clone()).to_string(), "server", room);
+        Server::say(list.as_mut_slice(), &joinmsg);
+
+        // Tell them what the room is about.
+        self.tell_topic(room, index);
+
+        for plugin in self.plugins.iter_mut() {
+            plugin.after_join(&name, room);
+        }
+
+        StatusCode::Ok
+    }
+
+    // Says a message in a room, unless a plugin objects. Plugins may also
+    // change what is said. The code tells speech from actions.
+    fn on_say(&mut self, room: &str, user: &str, message: &str, code: usize) -> Result<(), StatusCode> {
+        let mut body = message.to_string();
+        for plugin in self.plugins.iter_mut() {
+            plugin.before_say(user, room, &mut body)?;
+        }
+
         if let Some(recipients) = self.rooms.get_mut(room) {
-            let message = Server::create_message(0, message, user, room);
+            let message = Server::create_message(code, &body, user, room);
             Server::say(recipients.as_mut_slice(), &message);
         }
+
+        Ok(())
+    }
+
+    // Says a message in every room the client at `index` is in. Rooms where
+    // a plugin turns the message down are skipped.
+    fn on_shout(&mut self, index: usize, message: &str, code: usize) {
+        let user = self.clients[index].name.clone();
+        let rooms: Vec<_> = self.clients[index].rooms.iter().cloned().collect();
+
+        for room in rooms {
+            let _ = self.on_say(&room, &user, message, code);
+        }
+    }
+
+    // Passes a message from the client at `index` on to another client.
+    fn on_whisper(&mut self, index: usize, to: &str, message: &str, code: usize) -> StatusCode {
+        // Whoever holds a registered name without its password isn't the
+        // one whispers to it are meant for.
+        let recipient = match self.clients.iter().find(|c| c.name.eq(to)) {
+            Some(recipient) if self.trusted(recipient) => recipient.clone(),
+            _ => return StatusCode::UserDoesntExist,
+        };
+
+        let message = Server::create_message(code, message, &self.clients[index].name, to);
+        Server::say(&mut [recipient.clone()], &message);
+
+        // Let the sender know nobody may be reading. The notice is about
+        // someone rather than a room, so it goes to "*".
+        if let Some(away) = recipient.away {
+            let notice = Notice::Away(to.to_string(), away).to_string();
+            let message = Server::create_message(0, &notice, "server", "*");
+            Server::say(&mut [self.clients[index].clone()], &message);
+        }
+
+        StatusCode::Ok
+    }
+
+    // Changes the name of the client at `index` and tells everyone who
+    // shares a room with them.
+    fn rename(&mut self, index: usize, new_name: &str) -> StatusCode {
+        if reserved(new_name) || self.clients.iter().any(|c| c.name.eq(new_name)) {
+            return StatusCode::UsernameUnavailable;
+        }
+
+        // Passwords go with names.
+        let old_name = mem::replace(&mut self.clients[index].name, new_name.to_string());
+        self.clients[index].authenticated = false;
+        self.remind_registered(index);
+
+        for room in self.clients[index].rooms.iter() {
+            if let Some(members) = self.rooms.get_mut(room) {
+                for member in members.iter_mut().filter(|c| c.name.eq(&old_name)) {
+                    member.name = new_name.to_string();
+                }
+            }
+        }
+        self.announce(index, Notice::Nick(old_name, new_name.to_string()));
+
+        StatusCode::Ok
+    }
+
+    // Disconnects the client at `index`, leaving each of their rooms with
+    // the given notice.
+    fn disconnect(&mut self, index: usize, notice: Notice) {
+        let client = self.clients[index].clone();
+
+        // unsubscribe them from each room they belong to.
+        let subscribed: Vec<_> = client.rooms.iter().cloned().collect();
+        for room in subscribed {
+            self.on_leave(&room, index, notice.clone());
+        }
+
+        ignore_result(client.connection.shutdown(net::Shutdown::Both));
+
+        // remove from list of clients
+        self.clients.remove(index);
+
+        for plugin in self.plugins.iter_mut() {
+            plugin.after_quit(&client.name);
+        }
+    }
+
+    // Disconnects the client at `index` on behalf of the operator `by`,
+    // telling them who did it first.
+    fn kill(&mut self, index: usize, by: &str) {
+        let name = self.clients[index].name.clone();
+        let notice = Notice::Kill(by.to_string(), name.clone());
+
+        let message = Server::create_message(0, &notice.to_string(), "server", "*");
+        Server::say(&mut [self.clients[index].clone()], &message);
+
+        self.disconnect(index, notice);
+    }
+
+    // Whether the connection comes from a banned address.
+    fn banned(&self, connection: &Connection) -> bool {
+        connection.peer_addr().is_ok_and(|a| self.bans.iter().any(|range| range.contains(a.ip())))
+    }
+
+    // Reads the config file again. The addresses to listen on and the
+    // services file stay as they were until the server restarts.
+    fn reload(&mut self) -> Result<(), String> {
+        let config = match self.config_path {
+            Some(ref path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        // Plugins whose settings haven't changed carry on as they were,
+        // keeping what they know; the others start afresh. Plugins are
+        // kept in the same order as their settings.
+        let mut used = vec![false; self.plugins.len()];
+        let kept: Vec<Option<usize>> = config.plugins.iter().map(|settings| {
+            let old = (0..used.len()).find(|&i| !used[i] && self.config.plugins[i] == *settings);
+            if let Some(i) = old {
+                used[i] = true;
+            }
+            old
+        }).collect();
+
+        let added: Vec<_> = config.plugins.iter().zip(kept.iter())
+            .filter(|&(_, old)| old.is_none())
+            .map(|(settings, _)| settings.clone())
+            .collect();
+        let mut added = plugin::load(&added)?.into_iter();
+
+        let mut old: Vec<_> = self.plugins.drain(..).map(Some).collect();
+        self.plugins = kept.into_iter()
+            .map(|k| match k {
+                Some(i) => old[i].take().expect("kept plugin"),
+                None => added.next().expect("added plugin"),
+            })
+            .collect();
+
+        // Nobody stays an operator or admin on the strength of an account
+        // or password that is gone.
+        for client in self.clients.iter_mut() {
+            if client.oper.as_ref().is_some_and(|name| !config.opers.iter().any(|o| o.name == *name)) {
+                client.oper = None;
+            }
+            if config.admin_password != self.config.admin_password {
+                client.admin = false;
+            }
+        }
+
+        self.config = config;
+
+        Ok(())
+    }
+
+    // A registered room is in the hands of its founder, once they have
+    // identified to nickserv. Anywhere else, whoever has been in the room
+    // the longest is in charge.
+    fn in_charge(&self, room: &str, index: usize) -> bool {
+        let client = &self.clients[index];
+
+        match self.services.founder(room) {
+            Some(founder) => founder == client.name && client.authenticated,
+            None => self.rooms.get(room).and_then(|members| members.first()).is_some_and(|c| c.name.eq(&client.name)),
+        }
+    }
+
+    // Whether a client is who their name says: they have identified to
+    // nickserv, or nobody has registered the name.
+    fn trusted(&self, client: &Client) -> bool {
+        client.authenticated || !self.services.registered(&client.name)
     }
 
-    // Gracefully unsubscribes user from the room.
-    fn on_leave(&mut self, room: &str, user: &str, index: usize) {
+    // Has nickserv let the client at `index` know if their name is
+    // registered and needs a password before they can use it.
+    fn remind_registered(&self, index: usize) {
+        let client = &self.clients[index];
+        if !self.services.registered(&client.name) {
+            return;
+        }
+
+        let text = "This name is registered. Whisper nickserv IDENTIFY <password> to use it, or pick another name.";
+        let message = Server::create_message(0, text, "nickserv", &client.name);
+        Server::say(&mut [client.clone()], &message);
+    }
+
+    // Sends a notice to every room the client at `index` is in.
+    fn announce(&mut self, index: usize, notice: Notice) {
+        let notice = notice.to_string();
+
+        for room in self.clients[index].rooms.iter() {
+            if let Some(members) = self.rooms.get_mut(room) {
+                let message = Server::create_message(0, &notice, "server", room);
+                Server::say(members.as_mut_slice(), &message);
+            }
+        }
+    }
+
+    // Sends the client at `index` the room's topic, if it has one.
+    fn tell_topic(&self, room: &str, index: usize) {
+        if let Some((setter, topic)) = self.topics.get(room) {
+            let notice = Notice::Topic(setter.clone(), topic.clone());
+            let message = Server::create_message(0, &notice.to_string(), "server", room);
+            Server::say(&mut [self.clients[index].clone()], &message);
+        }
+    }
+
+    // Gracefully unsubscribes the client at `index` from the room, telling
+    // everyone in it, them included, why they went.
+    fn on_leave(&mut self, room: &str, index: usize, notice: Notice) {
+        let user = self.clients[index].name.clone();
+
         // If the client is subscribed to the room
         if self.clients[index].rooms.contains(room) {
             // If the room actually exists
-            if let Some(subscribed) = self.rooms.get_mut(&room.to_string()) {
+            if let Some(subscribed) = self.rooms.get_mut(room) {
                 // Announce that the user is leaving.
-                let message = Server::create_message(0, &format!("{} has left.", user), "server", room);
+                let message = Server::create_message(0, &notice.to_string(), "server", room);
                 Server::say(subscribed.as_mut_slice(), &message);
 
                 if let Some(cindex) = subscribed.iter().position(|c| c.name.eq(&user)) {
@@ -229,11 +876,12 @@ impl Server {
             self.clients[index].rooms.remove(room);
             let empties: Vec<_> = self.rooms
                 .iter()
-                .filter(|&(_, ref v)| v.len() == 0)
+                .filter(|(_, v)| v.is_empty())
                 .map(|(k, _)| k.clone())
                 .collect();
             for empty in empties {
                 self.rooms.remove(&empty);
+                self.topics.remove(&empty);
             }
         }
     }
@@ -241,7 +889,7 @@ impl Server {
     // Sends a message to specified clients.
     fn say(to: &mut[Client], what: &str) {
         for client in to {
-            ignore_result(client.connection.write(format!("{}", what).as_bytes()));
+            ignore_result(client.connection.write_all(what.as_bytes()));
             ignore_result(client.connection.flush());
         }
     }
@@ -249,20 +897,19 @@ impl Server {
     // Creates a formatted message
     // <opcode> <sender> <timestamp> <room> <message>
     fn create_message(code: usize, body: &str, from: &str, to_room: &str) -> String {
-        let time = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-            Ok(t) => t.as_secs(),
-            _ => 0,
-        };
-
-        format!("{} {} {} {} {}\n", code, from, time, to_room, body)
+        format!("{} {} {} {} {}\n", code, from, unix_time(), to_room, body)
     }
 }
 
+// Names nobody may take: "server", so that nobody can fake notices; the
+// services', so nobody can pretend to be them; and names that would look
+// like someone away in a LIST.
+fn reserved(name: &str) -> bool {
+    name == "server" || is_service(name) || name.ends_with(AWAY_SUFFIX)
+}
+
 // Reckless utility function; there are times where
 // I am just making sure something has been shut down
 // and don't care if it has already been shut down.
-fn ignore_result<R, E>(r: Result<R, E>) {
-    match r {
-        _ => (),
-    }
+fn ignore_result<R, E>(_: Result<R, E>) {
 }
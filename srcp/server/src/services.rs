@@ -0,0 +1,274 @@
+use ::ring::{digest, pbkdf2, rand};
+use ::ring::rand::SecureRandom;
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::collections::HashMap;
+
+use server::Client;
+use throttle::{self, Throttle};
+
+const ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+// How long to wait between password attempts from one address, or for one
+// name. Hashing a password takes long enough that someone sending nothing
+// else could otherwise hold up everyone, besides guessing quickly.
+pub const PASSWORD_COOLDOWN: Duration = Duration::from_secs(2);
+pub const TOO_MANY_ATTEMPTS: &str = "Too many attempts. Wait a moment and try again.";
+
+const NICKSERV_HELP: &str = "Commands: REGISTER <password> registers your current name, \
+    IDENTIFY <password> proves it is yours, DROP <password> gives it up.";
+const ROOMSERV_HELP: &str = "Commands: REGISTER <room> makes you its founder, \
+    DROP <room> gives it up, INFO <room> tells who founded it. \
+    You need a registered name to found rooms.";
+
+// A registered nickname's password, salted and hashed.
+struct Account {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl Account {
+    fn new(password: &str) -> Account {
+        let mut salt = vec![0; SALT_LEN];
+        rand::SystemRandom::new().fill(&mut salt).expect("random salt");
+
+        let mut hash = vec![0; digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), &salt, password.as_bytes(), &mut hash);
+
+        Account { salt, hash }
+    }
+
+    fn check(&self, password: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), &self.salt, password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+// The state behind the service users: nicknames registered with nickserv
+// and rooms registered with roomserv. Registrations are kept in a file, if
+// the config names one, one per line:
+//
+//     nick alice <salt> <hash>
+//     room general alice
+//
+// Without a file they last until the server stops.
+pub struct Services {
+    file: Option<PathBuf>,
+    nicks: HashMap<String, Account>,
+    // Each registered room's founder.
+    rooms: HashMap<String, String>,
+    passwords: Throttle,
+}
+
+impl Services {
+    pub fn load(file: Option<PathBuf>) -> Result<Services, String> {
+        let mut services = Services {
+            file: None,
+            nicks: HashMap::new(),
+            rooms: HashMap::new(),
+            passwords: Throttle::new(PASSWORD_COOLDOWN),
+        };
+
+        let path = match file {
+            Some(path) => path,
+            None => return Ok(services),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            // Nothing has been registered yet.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+        };
+
+        for (number, line) in contents.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                ["nick", nick, salt, hash] => {
+                    let account = match (from_hex(salt), from_hex(hash)) {
+                        (Some(salt), Some(hash)) => Account { salt, hash },
+                        _ => return Err(format!("{}:{}: bad password hash", path.display(), number + 1)),
+                    };
+                    services.nicks.insert(nick.to_string(), account);
+                },
+                ["room", room, founder] => {
+                    services.rooms.insert(room.to_string(), founder.to_string());
+                },
+                _ => return Err(format!("{}:{}: cannot parse registration", path.display(), number + 1)),
+            }
+        }
+
+        services.file = Some(path);
+        Ok(services)
+    }
+
+    pub fn registered(&self, nick: &str) -> bool {
+        self.nicks.contains_key(nick)
+    }
+
+    pub fn founder(&self, room: &str) -> Option<&str> {
+        self.rooms.get(room).map(String::as_str)
+    }
+
+    // Answers a whisper to nickserv from `nick` at `address`, who has
+    // `authenticated` if they gave the password for their current name.
+    pub fn nickserv(&mut self, nick: &str, address: IpAddr, authenticated: &mut bool, text: &str) -> String {
+        let words: Vec<&str> = text.split_whitespace().collect();
+
+        match words.as_slice() {
+            [command, password] if command.eq_ignore_ascii_case("REGISTER") => {
+                if self.registered(nick) {
+                    return format!("{} is already registered.", nick);
+                }
+                if !self.may_try(address, nick) {
+                    return String::from(TOO_MANY_ATTEMPTS);
+                }
+
+                self.nicks.insert(nick.to_string(), Account::new(password));
+                *authenticated = true;
+                self.save();
+
+                format!("{} is now registered to you.", nick)
+            },
+            [command, password] if command.eq_ignore_ascii_case("IDENTIFY") => {
+                if let Err(answer) = self.check(nick, address, password) {
+                    return answer;
+                }
+
+                *authenticated = true;
+                format!("You are now identified as {}.", nick)
+            },
+            [command, password] if command.eq_ignore_ascii_case("DROP") => {
+                if let Err(answer) = self.check(nick, address, password) {
+                    return answer;
+                }
+
+                // Rooms go with the name that founded them.
+                self.nicks.remove(nick);
+                self.rooms.retain(|_, founder| founder != nick);
+                *authenticated = false;
+                self.save();
+
+                format!("{} is no longer registered.", nick)
+            },
+            _ => String::from(NICKSERV_HELP),
+        }
+    }
+
+    // Checks the password for a registered `nick`, or says what was wrong.
+    fn check(&mut self, nick: &str, address: IpAddr, password: &str) -> Result<(), String> {
+        if !self.registered(nick) {
+            return Err(format!("{} is not registered.", nick));
+        }
+        if !self.may_try(address, nick) {
+            return Err(String::from(TOO_MANY_ATTEMPTS));
+        }
+
+        match self.nicks[nick].check(password) {
+            true => Ok(()),
+            false => Err(String::from("Wrong password.")),
+        }
+    }
+
+    // Whether a password can be hashed for `nick` at `address` yet.
+    fn may_try(&mut self, address: IpAddr, nick: &str) -> bool {
+        self.passwords.attempt(&[throttle::address_key(address), format!("nick {}", nick)])
+    }
+
+    // Answers a whisper to roomserv from `nick`.
+    pub fn roomserv(&mut self, nick: &str, authenticated: bool, rooms: &HashMap<String, Vec<Client>>, text: &str) -> String {
+        let words: Vec<&str> = text.split_whitespace().collect();
+
+        match words.as_slice() {
+            [command, room] if command.eq_ignore_ascii_case("REGISTER") => {
+                if !self.registered(nick) || !authenticated {
+                    return String::from("Register your name with nickserv, or identify to it, first.");
+                }
+                if let Some(founder) = self.founder(room) {
+                    return format!("{} is already registered to {}.", room, founder);
+                }
+
+                // Only whoever is in charge of a room can register it, so
+                // nobody can take over a room out from under its people.
+                match rooms.get(*room) {
+                    None => format!("{} doesn't exist.", room),
+                    Some(members) if !members.first().is_some_and(|c| c.name.eq(nick)) => {
+                        format!("Only whoever has been in {} the longest can register it.", room)
+                    },
+                    Some(_) => {
+                        self.rooms.insert(room.to_string(), nick.to_string());
+                        self.save();
+
+                        format!("{} is now registered to you.", room)
+                    },
+                }
+            },
+            [command, room] if command.eq_ignore_ascii_case("DROP") => {
+                match self.founder(room) {
+                    None => format!("{} is not registered.", room),
+                    Some(founder) if founder != nick || !authenticated => {
+                        format!("Only {}'s founder can drop it.", room)
+                    },
+                    Some(_) => {
+                        self.rooms.remove(*room);
+                        self.save();
+
+                        format!("{} is no longer registered.", room)
+                    },
+                }
+            },
+            [command, room] if command.eq_ignore_ascii_case("INFO") => {
+                match self.founder(room) {
+                    Some(founder) => format!("{} was founded by {}.", room, founder),
+                    None => format!("{} is not registered.", room),
+                }
+            },
+            _ => String::from(ROOMSERV_HELP),
+        }
+    }
+
+    // Writes every registration out, if there is somewhere to write them.
+    fn save(&self) {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let mut contents = String::new();
+        for (nick, account) in self.nicks.iter() {
+            contents.push_str(&format!("nick {} {} {}\n", nick, to_hex(&account.salt), to_hex(&account.hash)));
+        }
+        for (room, founder) in self.rooms.iter() {
+            contents.push_str(&format!("room {} {}\n", room, founder));
+        }
+
+        if let Err(e) = fs::write(path, contents) {
+            eprintln!("services: cannot write to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn iterations() -> NonZeroU32 {
+    NonZeroU32::new(ITERATIONS).expect("nonzero iterations")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
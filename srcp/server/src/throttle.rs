@@ -0,0 +1,89 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+
+// Slows down password guessing. Each attempt is recorded under a few keys,
+// like the address it came from and the name it was for, and another
+// attempt under any of them has to wait. Connecting again or from several
+// connections at once doesn't help.
+pub struct Throttle {
+    every: Duration,
+    last: HashMap<String, Instant>,
+}
+
+impl Throttle {
+    pub fn new(every: Duration) -> Throttle {
+        Throttle {
+            every,
+            last: HashMap::new(),
+        }
+    }
+
+    // Records an attempt under all of `keys`, unless one of them was tried
+    // too recently, in which case it returns false.
+    pub fn attempt(&mut self, keys: &[String]) -> bool {
+        let now = Instant::now();
+        let every = self.every;
+        self.last.retain(|_, last| now.duration_since(*last) < every);
+
+        if keys.iter().any(|key| self.last.contains_key(key)) {
+            return false;
+        }
+
+        for key in keys {
+            self.last.insert(key.clone(), now);
+        }
+
+        true
+    }
+}
+
+// The key for attempts from `address`. Someone with an IPv6 network
+// usually has all of a /64 to pick addresses from, so that is what counts.
+pub fn address_key(address: IpAddr) -> String {
+    match address.to_canonical() {
+        IpAddr::V4(a) => format!("addr {}", a),
+        IpAddr::V6(a) => {
+            let network = u128::from(a) & !(u128::MAX >> 64);
+            format!("addr {}/64", ::std::net::Ipv6Addr::from(network))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn any_shared_key_has_to_wait() {
+        let mut throttle = Throttle::new(Duration::from_secs(60));
+
+        assert!(throttle.attempt(&keys(&["addr 192.0.2.1", "nick alice"])));
+        assert!(!throttle.attempt(&keys(&["addr 192.0.2.1", "nick bob"])));
+        assert!(!throttle.attempt(&keys(&["addr 192.0.2.2", "nick alice"])));
+        assert!(throttle.attempt(&keys(&["addr 192.0.2.2", "nick bob"])));
+    }
+
+    #[test]
+    fn waiting_long_enough_is_fine() {
+        let mut throttle = Throttle::new(Duration::from_millis(0));
+
+        assert!(throttle.attempt(&keys(&["addr 192.0.2.1"])));
+        assert!(throttle.attempt(&keys(&["addr 192.0.2.1"])));
+    }
+
+    #[test]
+    fn addresses_share_a_key_with_their_network() {
+        let key = |s: &str| address_key(s.parse().unwrap());
+
+        assert_eq!(key("192.0.2.1"), "addr 192.0.2.1");
+        assert_eq!(key("::ffff:192.0.2.1"), "addr 192.0.2.1");
+        assert_eq!(key("2001:db8::1"), "addr 2001:db8::/64");
+        assert_eq!(key("2001:db8::1:2:3:4"), key("2001:db8::5"));
+        assert!(key("2001:db8:0:1::1") != key("2001:db8::1"));
+    }
+}
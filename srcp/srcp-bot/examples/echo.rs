@@ -0,0 +1,36 @@
+// Says back whatever follows !echo, in the room or by whispering.
+//
+//     $ cargo run -p srcp-bot --example echo -- localhost:6667 general
+extern crate srcp_bot;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+use srcp_bot::{Bot, Security};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let addr = args.first().map_or("localhost:6667", String::as_str);
+
+    let mut bot = Bot::new("echobot", ());
+    for room in args.iter().skip(1) {
+        bot.join(room);
+    }
+
+    // Lets people whisper the bot from the chat client.
+    if let Err(e) = bot.keys(Path::new("echobot-keys")) {
+        eprintln!("echobot: cannot load keys: {}", e);
+        process::exit(1);
+    }
+
+    bot.command("echo", |_, request| vec![request.reply(request.args)]);
+    bot.mention(|_, request| {
+        vec![request.reply(&format!("{}: say !echo and something to echo.", request.message.sender))]
+    });
+
+    if let Err(e) = bot.run(addr, &Security::Plain) {
+        eprintln!("echobot: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,85 @@
+// Reminds people of things after a while:
+//
+//     !remind 10m take the bread out
+//
+// takes 30s, 10m or 2h, and answers in the room or by whispering,
+// wherever it was asked.
+//
+//     $ cargo run -p srcp-bot --example reminder -- localhost:6667 general
+extern crate common;
+extern crate srcp_bot;
+
+use std::env;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+use common::Command;
+use srcp_bot::{Bot, Security};
+
+// How often due reminders are looked for.
+const CHECK_EVERY: Duration = Duration::from_secs(1);
+
+// A reminder waiting to go off: when, and the command that delivers it.
+type Reminder = (Instant, Command);
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let addr = args.first().map_or("localhost:6667", String::as_str);
+
+    let mut bot = Bot::new("reminder", Vec::<Reminder>::new());
+    for room in args.iter().skip(1) {
+        bot.join(room);
+    }
+
+    // Lets people whisper the bot from the chat client.
+    if let Err(e) = bot.keys(Path::new("reminder-keys")) {
+        eprintln!("reminder: cannot load keys: {}", e);
+        process::exit(1);
+    }
+
+    bot.command("remind", |reminders, request| {
+        let sender = &request.message.sender;
+        let mut words = request.args.splitn(2, char::is_whitespace);
+        let when = words.next().unwrap_or_default();
+        let what = words.next().map(str::trim).unwrap_or_default();
+
+        // Too long a wait has no Instant to go off at.
+        match parse_duration(when).map(|after| Instant::now().checked_add(after)) {
+            Some(Some(due)) if !what.is_empty() => {
+                let text = format!("{}: reminder: {}", sender, what);
+                reminders.push((due, request.reply(&text)));
+                vec![request.reply(&format!("{}: OK, in {}.", sender, when))]
+            },
+            Some(None) => vec![request.reply(&format!("{}: {} is too long to wait.", sender, when))],
+            _ => vec![request.reply("Usage: !remind 10m something to remember (s, m or h)")],
+        }
+    });
+
+    bot.every(CHECK_EVERY, |reminders| {
+        let now = Instant::now();
+        let (due, waiting): (Vec<Reminder>, Vec<Reminder>) = reminders.drain(..).partition(|r| r.0 <= now);
+        *reminders = waiting;
+
+        due.into_iter().map(|(_, command)| command).collect()
+    });
+
+    if let Err(e) = bot.run(addr, &Security::Plain) {
+        eprintln!("reminder: {}", e);
+        process::exit(1);
+    }
+}
+
+// Reads durations like 30s, 10m and 2h. Ones too long to count in seconds
+// come back as Duration::MAX.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        _ => return None,
+    };
+
+    let count: u64 = text[..text.len() - 1].parse().ok()?;
+    Some(count.checked_mul(unit).map_or(Duration::MAX, Duration::from_secs))
+}
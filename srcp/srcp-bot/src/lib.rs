@@ -0,0 +1,310 @@
+// A small framework for srcp bots. A bot is a name, the rooms it sits in,
+// some state of its own and handlers that look at messages and answer with
+// commands:
+//
+//     let mut bot = Bot::new("echobot", ());
+//     bot.join("general");
+//     bot.command("echo", |_, request| vec![request.reply(request.args)]);
+//     bot.run("localhost:6667", &Security::Plain)?;
+//
+// Handlers see messages as `common::Message` and answer with
+// `common::Command`, so bots keep speaking the protocol as it changes.
+// The bot takes care of connecting, identifying, joining its rooms and
+// reconnecting when the connection drops. Given a place to keep keys, it
+// also publishes a whisper key and encrypts whispers like the chat client.
+
+extern crate common;
+extern crate srcp_client;
+
+use std::io;
+use std::thread;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+
+use common::{Command, Message, StatusCode};
+use srcp_client::{Client, Event};
+use srcp_client::crypto::{self, Keyring};
+
+pub use srcp_client::{Security, TlsOptions};
+
+// What goes in front of a word to make it a command for bots.
+pub const COMMAND_PREFIX: char = '!';
+
+// How long to wait before reconnecting, doubling after each failed attempt
+// up to the longest wait.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+// A message a handler was called for.
+pub struct Request<'a> {
+    pub message: &'a Message,
+    // For commands, whatever follows the command's name; otherwise the
+    // whole body.
+    pub args: &'a str,
+    // Whether the message was whispered to the bot rather than said in a
+    // room.
+    pub whisper: bool,
+}
+
+impl<'a> Request<'a> {
+    // Answers the message where it came from: in the room it was said in,
+    // or by whispering back.
+    pub fn reply(&self, text: &str) -> Command {
+        if self.whisper {
+            Command::Whisper(self.message.sender.clone(), text.to_string())
+        } else {
+            Command::Say(self.message.room.clone(), text.to_string())
+        }
+    }
+}
+
+// Why a connection to the server ended.
+enum Ended {
+    // The connection failed; worth trying again.
+    Lost(io::Error),
+    // The server won't have the bot; no point trying again.
+    Refused(io::Error),
+}
+
+impl From<io::Error> for Ended {
+    fn from(e: io::Error) -> Ended {
+        Ended::Lost(e)
+    }
+}
+
+type Handler<S> = Box<dyn FnMut(&mut S, &Request) -> Vec<Command>>;
+type Job<S> = Box<dyn FnMut(&mut S) -> Vec<Command>>;
+
+struct Task<S> {
+    every: Duration,
+    next: Instant,
+    run: Job<S>,
+}
+
+pub struct Bot<S> {
+    nick: String,
+    rooms: Vec<String>,
+    state: S,
+    commands: HashMap<String, Handler<S>>,
+    mentions: Vec<Handler<S>>,
+    tasks: Vec<Task<S>>,
+    keyring: Option<Keyring>,
+}
+
+impl<S> Bot<S> {
+    pub fn new(nick: &str, state: S) -> Bot<S> {
+        Bot {
+            nick: nick.to_string(),
+            rooms: vec![],
+            state,
+            commands: HashMap::new(),
+            mentions: vec![],
+            tasks: vec![],
+            keyring: None,
+        }
+    }
+
+    // Keeps the bot's whisper key, and the keys of those who whisper it, in
+    // `dir`. The bot then publishes its key, so that the chat client will
+    // whisper it, and encrypts its whispers to anyone whose key it has
+    // learned from an encrypted whisper. There is no one to compare
+    // fingerprints with, so the bot takes whatever key the server hands
+    // out.
+    pub fn keys(&mut self, dir: &Path) -> io::Result<()> {
+        self.keyring = Some(Keyring::load(dir.to_path_buf())?);
+        Ok(())
+    }
+
+    // Adds a room to join on connecting.
+    pub fn join(&mut self, room: &str) {
+        self.rooms.push(room.to_string());
+    }
+
+    // Handles `!name args` said in a room or whispered to the bot.
+    pub fn command<F>(&mut self, name: &str, handler: F)
+        where F: FnMut(&mut S, &Request) -> Vec<Command> + 'static {
+        self.commands.insert(name.to_string(), Box::new(handler));
+    }
+
+    // Handles messages in rooms that mention the bot by name and aren't
+    // commands.
+    pub fn mention<F>(&mut self, handler: F)
+        where F: FnMut(&mut S, &Request) -> Vec<Command> + 'static {
+        self.mentions.push(Box::new(handler));
+    }
+
+    // Runs a task every so often while connected, starting one interval
+    // after the bot starts.
+    pub fn every<F>(&mut self, every: Duration, task: F)
+        where F: FnMut(&mut S) -> Vec<Command> + 'static {
+        self.tasks.push(Task {
+            every,
+            next: Instant::now() + every,
+            run: Box::new(task),
+        });
+    }
+
+    // Connects and handles messages for as long as the server accepts the
+    // bot's name, reconnecting whenever the connection is lost.
+    pub fn run(&mut self, addr: &str, security: &Security) -> io::Result<()> {
+        let mut delay = RECONNECT_DELAY;
+
+        loop {
+            let error = match self.session(addr, security, &mut delay) {
+                Err(Ended::Lost(e)) => e,
+                Err(Ended::Refused(e)) => return Err(e),
+                Ok(()) => return Ok(()),
+            };
+
+            eprintln!("{}: {}; reconnecting in {}s", self.nick, error, delay.as_secs());
+            thread::sleep(delay);
+            delay = std::cmp::min(delay * 2, RECONNECT_DELAY_MAX);
+        }
+    }
+
+    // One connection to the server, until it fails. The reconnect delay
+    // starts over once the server has accepted the bot's name.
+    fn session(&mut self, addr: &str, security: &Security, delay: &mut Duration) -> Result<(), Ended> {
+        let mut client = Client::connect(addr, security)?;
+
+        let tag = client.identify(&self.nick)?;
+        // Anything but Ok, such as a banned address, would be refused again
+        // on reconnecting.
+        match client.wait_for(tag)?.code {
+            StatusCode::Ok => {},
+            StatusCode::UsernameUnavailable => {
+                let e = io::Error::new(io::ErrorKind::AddrInUse, format!("the name {} is taken", self.nick));
+                return Err(Ended::Refused(e));
+            },
+            code => {
+                let e = io::Error::other(format!("the server refused the name {} ({:?})", self.nick, code));
+                return Err(Ended::Refused(e));
+            },
+        }
+        *delay = RECONNECT_DELAY;
+
+        if let Some(ref keyring) = self.keyring {
+            client.publish_key(&keyring.public_key())?;
+        }
+
+        for room in self.rooms.clone() {
+            client.join(&room)?;
+        }
+
+        loop {
+            for event in client.poll()? {
+                let commands = match event {
+                    Event::Said(ref m) => self.on_message(m, false),
+                    Event::Whispered(m) => match self.decrypt(&mut client, m)? {
+                        Some(m) => self.on_message(&m, true),
+                        None => vec![],
+                    },
+                    Event::Reply(ref reply) if !reply.ok() => {
+                        eprintln!("{}: {} failed: {:?}", self.nick, reply.command, reply.code);
+                        vec![]
+                    },
+                    _ => vec![],
+                };
+
+                for command in commands {
+                    send(&mut client, self.keyring.as_ref(), command)?;
+                }
+            }
+
+            let now = Instant::now();
+            for task in self.tasks.iter_mut().filter(|t| t.next <= now) {
+                task.next = now + task.every;
+                for command in (task.run)(&mut self.state) {
+                    send(&mut client, self.keyring.as_ref(), command)?;
+                }
+            }
+        }
+    }
+
+    // Opens an encrypted whisper, asking the server for the sender's key
+    // if we don't have it or it no longer fits. Whispers that can't be
+    // opened are dropped.
+    fn decrypt(&mut self, client: &mut Client, mut m: Message) -> io::Result<Option<Message>> {
+        if !m.body.starts_with(crypto::WHISPER_PREFIX) {
+            return Ok(Some(m));
+        }
+
+        let keyring = match self.keyring {
+            Some(ref mut keyring) => keyring,
+            None => return Ok(None),
+        };
+
+        if let Ok(plaintext) = keyring.open(&m.sender, &m.body) {
+            m.body = plaintext;
+            return Ok(Some(m));
+        }
+
+        // The server appends the key to the echoed GETKEY.
+        let tag = client.get_key(&m.sender)?;
+        let reply = client.wait_for(tag)?;
+        let opened = match reply.body.split_whitespace().nth(2) {
+            Some(key) if reply.ok() => keyring.learn(&m.sender, key)
+                .and_then(|_| keyring.open(&m.sender, &m.body)),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "no public key available")),
+        };
+
+        match opened {
+            Ok(plaintext) => {
+                m.body = plaintext;
+                Ok(Some(m))
+            },
+            Err(e) => {
+                eprintln!("{}: cannot decrypt whisper from {}: {}", self.nick, m.sender, e);
+                Ok(None)
+            },
+        }
+    }
+
+    fn on_message(&mut self, m: &Message, whisper: bool) -> Vec<Command> {
+        // The server shows the bot its own messages, too.
+        if m.sender == self.nick {
+            return vec![];
+        }
+
+        if m.body.starts_with(COMMAND_PREFIX) {
+            let mut words = m.body[1..].splitn(2, char::is_whitespace);
+            let name = words.next().unwrap_or_default();
+            let args = words.next().unwrap_or_default().trim();
+
+            return match self.commands.get_mut(name) {
+                Some(handler) => handler(&mut self.state, &Request { message: m, args, whisper }),
+                None => vec![],
+            };
+        }
+
+        if whisper || !common::mentions(&m.body, &self.nick) {
+            return vec![];
+        }
+
+        let request = Request { message: m, args: &m.body, whisper };
+        let mut commands = vec![];
+        for handler in self.mentions.iter_mut() {
+            commands.extend(handler(&mut self.state, &request));
+        }
+
+        commands
+    }
+}
+
+// Sends a command, encrypting whispers to anyone whose key we know.
+fn send(client: &mut Client, keyring: Option<&Keyring>, command: Command) -> io::Result<()> {
+    let command = match (command, keyring) {
+        (Command::Whisper(to, text), Some(keyring)) if keyring.trust(&to).is_some() => {
+            let sealed = keyring.seal(&to, &text)?;
+            Command::Whisper(to, sealed)
+        },
+        (Command::Action(to, text), Some(keyring)) if to.starts_with('@') && keyring.trust(&to[1..]).is_some() => {
+            let sealed = keyring.seal(&to[1..], &text)?;
+            Command::Action(to, sealed)
+        },
+        (command, _) => command,
+    };
+
+    client.send(command).map(|_| ())
+}
@@ -0,0 +1,214 @@
+use ::std;
+use ::ring;
+use ::rustls;
+use ::webpki_roots;
+
+use std::fs;
+use std::io;
+use std::net;
+use std::sync::Arc;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::pki_types::pem::PemObject;
+
+// How the client should secure its connection to the server.
+pub enum Security {
+    Plain,
+    Tls(TlsOptions),
+}
+
+pub struct TlsOptions {
+    // Additional certificate authorities to trust, as a PEM file. The
+    // bundled web PKI roots are always trusted.
+    pub ca_file: Option<String>,
+    // SHA-256 fingerprint of the server's certificate. When set, the
+    // server is trusted if and only if it presents this exact certificate,
+    // which suits self-signed deployments.
+    pub pin: Option<String>,
+}
+
+pub enum Connection {
+    Plain(net::TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, net::TcpStream>>),
+}
+
+impl Connection {
+    // Connects to `addr`, completing the TLS handshake if asked to so that
+    // certificate problems are reported up front.
+    pub fn connect(addr: &str, security: &Security) -> io::Result<Connection> {
+        let mut stream = net::TcpStream::connect(addr)?;
+
+        match *security {
+            Security::Plain => Ok(Connection::Plain(stream)),
+            Security::Tls(ref options) => {
+                let config = client_config(options)?;
+                let name = ServerName::try_from(host(addr).to_string())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let mut conn = rustls::ClientConnection::new(Arc::new(config), name)
+                    .map_err(tls_error)?;
+
+                while conn.is_handshaking() {
+                    conn.complete_io(&mut stream)?;
+                }
+
+                Ok(Connection::Tls(Box::new(rustls::StreamOwned::new(conn, stream))))
+            },
+        }
+    }
+
+    fn socket(&self) -> &net::TcpStream {
+        match *self {
+            Connection::Plain(ref s) => s,
+            Connection::Tls(ref s) => s.get_ref(),
+        }
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<std::time::Duration>) -> io::Result<()> {
+        self.socket().set_read_timeout(dur)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Plain(ref mut s) => s.read(buf),
+            Connection::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Plain(ref mut s) => s.write(buf),
+            Connection::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Plain(ref mut s) => s.flush(),
+            Connection::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+fn client_config(options: &TlsOptions) -> io::Result<rustls::ClientConfig> {
+    let builder = rustls::ClientConfig::builder();
+
+    if let Some(ref pin) = options.pin {
+        let verifier = PinnedCertVerifier::new(pin)?;
+        return Ok(builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth());
+    }
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    if let Some(ref path) = options.ca_file {
+        let pem = fs::read(path)?;
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            let cert = cert.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            roots.add(cert).map_err(tls_error)?;
+        }
+    }
+
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+// Trusts exactly one certificate, identified by its SHA-256 fingerprint.
+// The chain and host name are not checked, but the handshake signatures
+// still are, so the server must hold the pinned certificate's key.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: Vec<u8>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertVerifier {
+    fn new(pin: &str) -> io::Result<PinnedCertVerifier> {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(crypto::ring::default_provider()));
+
+        Ok(PinnedCertVerifier {
+            fingerprint: parse_fingerprint(pin)?,
+            provider,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity.as_ref());
+
+        if digest.as_ref() == self.fingerprint.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(String::from("certificate does not match pinned fingerprint")))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Accepts fingerprints as printed by `openssl x509 -fingerprint -sha256`
+// ("AB:CD:...") or as plain hex.
+fn parse_fingerprint(pin: &str) -> io::Result<Vec<u8>> {
+    let hex: String = pin.chars().filter(|&c| c != ':').collect();
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid fingerprint: {}", pin));
+
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+// The host part of a "host:port" address, without IPv6 brackets.
+fn host(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(i) => &addr[..i],
+        None => addr,
+    };
+
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
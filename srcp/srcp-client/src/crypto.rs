@@ -0,0 +1,403 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::collections::HashMap;
+use std::os::unix::fs::OpenOptionsExt;
+use std::io::Write;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ring::aead;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// Encrypted whisper bodies look like "E2E <base64(nonce || ciphertext)>".
+pub const WHISPER_PREFIX: &str = "E2E";
+
+const IDENTITY_FILE: &str = "identity";
+const KNOWN_KEYS_FILE: &str = "known_keys";
+const KDF_LABEL: &[u8] = b"srcp whisper v1";
+
+// How far a peer's public key can be trusted.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Trust {
+    // Seen for the first time and accepted as is.
+    Unverified,
+    // The user compared fingerprints out of band and vouched for it.
+    Verified,
+    // Differs from the key previously stored for this name. Whispers to
+    // this peer are refused until the user verifies the new key.
+    Changed,
+}
+
+impl Trust {
+    pub fn label(self) -> &'static str {
+        match self {
+            Trust::Unverified => "unverified",
+            Trust::Verified => "verified",
+            Trust::Changed => "KEY CHANGED",
+        }
+    }
+}
+
+struct Peer {
+    key: PublicKey,
+    trust: Trust,
+    // What is on disk for a peer whose key has changed.
+    previous: Option<(PublicKey, Trust)>,
+}
+
+// The user's own key pair plus every peer key we have learned, persisted
+// in a directory of the caller's choosing (the chat client uses
+// ~/.config/srcp) so that trust decisions survive restarts.
+//
+// Whispers are encrypted with ChaCha20-Poly1305 under a key derived from
+// the X25519 shared secret of the two identities. Both directions share
+// the key, so each whisper is bound to its sender and recipient to stop
+// the server from passing one back to whoever wrote it. There is no
+// forward secrecy: a leaked identity key exposes past whispers.
+pub struct Keyring {
+    secret: StaticSecret,
+    public: PublicKey,
+    peers: HashMap<String, Peer>,
+    dir: PathBuf,
+    rng: SystemRandom,
+}
+
+impl Keyring {
+    // Loads the identity and known keys kept in `dir`, generating a new
+    // identity on first use.
+    pub fn load(dir: PathBuf) -> io::Result<Keyring> {
+        fs::create_dir_all(&dir)?;
+
+        let rng = SystemRandom::new();
+        let secret = match fs::read_to_string(dir.join(IDENTITY_FILE)) {
+            Ok(encoded) => StaticSecret::from(decode_key(encoded.trim())?),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut bytes = [0; 32];
+                rng.fill(&mut bytes).map_err(|_| random_error())?;
+                write_private(&dir.join(IDENTITY_FILE), &BASE64.encode(bytes))?;
+
+                StaticSecret::from(bytes)
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut peers = HashMap::new();
+        if let Ok(contents) = fs::read_to_string(dir.join(KNOWN_KEYS_FILE)) {
+            // name key trust
+            for line in contents.lines() {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() != 3 {
+                    continue;
+                }
+
+                let trust = if fields[2] == "verified" { Trust::Verified } else { Trust::Unverified };
+                if let Ok(key) = decode_key(fields[1]) {
+                    peers.insert(fields[0].to_string(), Peer { key: PublicKey::from(key), trust, previous: None });
+                }
+            }
+        }
+
+        Ok(Keyring {
+            public: PublicKey::from(&secret),
+            secret,
+            peers,
+            dir,
+            rng,
+        })
+    }
+
+    // Our public key, as published with KEY.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.public.as_bytes())
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+
+    pub fn peer_fingerprint(&self, name: &str) -> Option<String> {
+        self.peers.get(name).map(|p| fingerprint(&p.key))
+    }
+
+    pub fn trust(&self, name: &str) -> Option<Trust> {
+        self.peers.get(name).map(|p| p.trust)
+    }
+
+    // Records the key the server handed out for `name` and returns how
+    // far it can be trusted.
+    pub fn learn(&mut self, name: &str, key: &str) -> io::Result<Trust> {
+        let key = PublicKey::from(decode_key(key)?);
+
+        let previous = match self.peers.get(name) {
+            Some(peer) if peer.key == key => return Ok(peer.trust),
+            // Keep the old key on disk so the change is noticed again
+            // after a restart, until the user verifies the new one.
+            Some(peer) => Some(peer.previous.unwrap_or((peer.key, peer.trust))),
+            None => None,
+        };
+
+        let trust = if previous.is_some() { Trust::Changed } else { Trust::Unverified };
+        self.peers.insert(name.to_string(), Peer { key, trust, previous });
+        self.save()?;
+
+        Ok(trust)
+    }
+
+    // Marks the current key for `name` as verified by the user.
+    pub fn verify(&mut self, name: &str) -> io::Result<bool> {
+        match self.peers.get_mut(name) {
+            Some(peer) => {
+                peer.trust = Trust::Verified;
+                peer.previous = None;
+            },
+            None => return Ok(false),
+        }
+
+        self.save()?;
+
+        Ok(true)
+    }
+
+    // Encrypts a whisper body for `name`, whose key must already be known.
+    // Both keys are static and the nonce is random, with no counter, so
+    // nothing stops the server replaying an old whisper: it opens as often
+    // as it is sent.
+    pub fn seal(&self, name: &str, plaintext: &str) -> io::Result<String> {
+        let key = self.session_key(name)?;
+
+        let mut nonce = [0; aead::NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| random_error())?;
+
+        let peer = self.peer_key(name)?;
+        let aad = direction(&self.public, peer);
+
+        let mut sealed = plaintext.as_bytes().to_vec();
+        key.seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::from(aad), &mut sealed)
+            .map_err(|_| crypto_error())?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&sealed);
+
+        Ok(format!("{} {}", WHISPER_PREFIX, BASE64.encode(&payload)))
+    }
+
+    // Decrypts a whisper body from `name`, whose key must already be known.
+    pub fn open(&self, name: &str, body: &str) -> io::Result<String> {
+        let encoded = body.trim_start_matches(WHISPER_PREFIX).trim();
+        let payload = BASE64.decode(encoded).map_err(|_| crypto_error())?;
+        if payload.len() < aead::NONCE_LEN {
+            return Err(crypto_error());
+        }
+
+        let (nonce, sealed) = payload.split_at(aead::NONCE_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce).map_err(|_| crypto_error())?;
+
+        let peer = self.peer_key(name)?;
+        let aad = direction(peer, &self.public);
+
+        let mut sealed = sealed.to_vec();
+        let plaintext = self.session_key(name)?
+            .open_in_place(nonce, aead::Aad::from(aad), &mut sealed)
+            .map_err(|_| crypto_error())?;
+
+        String::from_utf8(plaintext.to_vec()).map_err(|_| crypto_error())
+    }
+
+    // Both ends derive the same key: the hash of the shared secret and
+    // both public keys in a fixed order.
+    fn session_key(&self, name: &str) -> io::Result<aead::LessSafeKey> {
+        let peer = self.peer_key(name)?;
+
+        let shared = self.secret.diffie_hellman(peer);
+        let (first, second) = if self.public.as_bytes() < peer.as_bytes() {
+            (&self.public, peer)
+        } else {
+            (peer, &self.public)
+        };
+
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(KDF_LABEL);
+        ctx.update(shared.as_bytes());
+        ctx.update(first.as_bytes());
+        ctx.update(second.as_bytes());
+        let hash = ctx.finish();
+
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, hash.as_ref())
+            .map_err(|_| crypto_error())?;
+
+        Ok(aead::LessSafeKey::new(key))
+    }
+
+    fn peer_key(&self, name: &str) -> io::Result<&PublicKey> {
+        self.peers.get(name)
+            .map(|p| &p.key)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no key for {}", name)))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut names: Vec<_> = self.peers.keys().collect();
+        names.sort();
+
+        let mut contents = String::new();
+        for name in names {
+            let peer = &self.peers[name];
+            let (key, trust) = peer.previous.unwrap_or((peer.key, peer.trust));
+            let trust = if trust == Trust::Verified { "verified" } else { "unverified" };
+            contents.push_str(&format!("{} {} {}\n", name, BASE64.encode(key.as_bytes()), trust));
+        }
+
+        fs::write(self.dir.join(KNOWN_KEYS_FILE), contents)
+    }
+}
+
+// The associated data of a whisper from `sender` to `recipient`, so that
+// it only opens for the recipient, as coming from the sender.
+fn direction(sender: &PublicKey, recipient: &PublicKey) -> Vec<u8> {
+    let mut aad = KDF_LABEL.to_vec();
+    aad.extend_from_slice(sender.as_bytes());
+    aad.extend_from_slice(recipient.as_bytes());
+
+    aad
+}
+
+// A short, human comparable digest of a public key.
+fn fingerprint(key: &PublicKey) -> String {
+    let hash = digest::digest(&digest::SHA256, key.as_bytes());
+    let groups: Vec<String> = hash.as_ref()[0..10]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect();
+
+    groups.join(" ")
+}
+
+fn decode_key(encoded: &str) -> io::Result<[u8; 32]> {
+    let bytes = BASE64.decode(encoded)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed key"))?;
+
+    if bytes.len() != 32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed key"));
+    }
+
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes);
+
+    Ok(key)
+}
+
+// Writes a file that only the user can read.
+fn write_private(path: &PathBuf, contents: &str) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+
+    file.write_all(contents.as_bytes())
+}
+
+fn random_error() -> io::Error {
+    io::Error::other("no randomness available")
+}
+
+fn crypto_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "cannot decrypt whisper")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static KEYRINGS: AtomicUsize = AtomicUsize::new(0);
+
+    // A keyring whose directory goes away along with it.
+    struct Scratch(Keyring);
+
+    impl Deref for Scratch {
+        type Target = Keyring;
+
+        fn deref(&self) -> &Keyring {
+            &self.0
+        }
+    }
+
+    impl DerefMut for Scratch {
+        fn deref_mut(&mut self) -> &mut Keyring {
+            &mut self.0
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.dir);
+        }
+    }
+
+    // A keyring of its own in a fresh directory.
+    fn keyring() -> Scratch {
+        let n = KEYRINGS.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!("srcp-keyring-{}-{}", process::id(), n));
+        let _ = fs::remove_dir_all(&dir);
+
+        Scratch(Keyring::load(dir).expect("keyring"))
+    }
+
+    fn pair() -> (Scratch, Scratch) {
+        let (mut alice, mut bob) = (keyring(), keyring());
+        alice.learn("bob", &bob.public_key()).expect("learn");
+        bob.learn("alice", &alice.public_key()).expect("learn");
+
+        (alice, bob)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (alice, bob) = pair();
+
+        let sealed = alice.seal("bob", "hello, bob").expect("seal");
+        assert!(sealed.starts_with(WHISPER_PREFIX));
+        assert!(!sealed.contains("hello"));
+        assert_eq!(bob.open("alice", &sealed).expect("open"), "hello, bob");
+    }
+
+    #[test]
+    fn tampering_is_rejected() {
+        let (alice, bob) = pair();
+
+        let sealed = alice.seal("bob", "hello, bob").expect("seal");
+        let mut payload = BASE64.decode(sealed.trim_start_matches(WHISPER_PREFIX).trim()).expect("base64");
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        let tampered = format!("{} {}", WHISPER_PREFIX, BASE64.encode(&payload));
+
+        assert!(bob.open("alice", &tampered).is_err());
+        assert!(bob.open("alice", "E2E not base64!").is_err());
+        assert!(bob.open("alice", "E2E AAAA").is_err());
+    }
+
+    #[test]
+    fn whispers_cannot_be_reflected() {
+        let (alice, _) = pair();
+
+        // Alice's own whisper to bob, handed back to her as if from bob.
+        let sealed = alice.seal("bob", "hello, bob").expect("seal");
+        assert!(alice.open("bob", &sealed).is_err());
+    }
+
+    #[test]
+    fn only_the_recipient_can_open() {
+        let (alice, _) = pair();
+        let mut eve = keyring();
+        eve.learn("alice", &alice.public_key()).expect("learn");
+
+        let sealed = alice.seal("bob", "hello, bob").expect("seal");
+        assert!(eve.open("alice", &sealed).is_err());
+    }
+}
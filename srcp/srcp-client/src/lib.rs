@@ -0,0 +1,380 @@
+// A connection to an srcp server for programs that want to talk to one
+// without caring about the wire format: the chat client, bots and other
+// integrations.
+//
+//     let mut client = Client::connect("localhost:6667", &Security::Plain)?;
+//     client.identify("bot")?;
+//     client.join("general")?;
+//
+//     for event in client.events() {
+//         if let Event::Said(m) = event? {
+//             println!("{} said {}", m.sender, m.body);
+//         }
+//     }
+//
+// Every command is sent with a tag of its own, returned by the method that
+// sent it, so that its reply can be told apart from the others.
+
+extern crate base64;
+extern crate common;
+extern crate ring;
+extern crate rustls;
+extern crate webpki_roots;
+extern crate x25519_dalek;
+
+mod connection;
+pub mod crypto;
+
+use std::io;
+use std::time;
+use std::io::{Read, Write};
+use std::collections::{HashMap, VecDeque};
+
+use common::{Command, Message, Notice, StatusCode};
+
+pub use connection::{Connection, Security, TlsOptions};
+
+// How long a read waits for the server before giving up, so that callers
+// polling for events can get on with other things.
+const READ_TIMEOUT: time::Duration = time::Duration::from_millis(85);
+
+// How long wait_for waits for a reply. The server answers every tagged
+// command at once, unless it never saw the tag, as when it turns down a
+// line it could not read with an untagged failure.
+const REPLY_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// Identifies a command sent to the server, and the reply to it.
+pub type Tag = u64;
+
+// Something the server told us.
+pub enum Event {
+    // Someone said something in a room we are in, or did something if the
+    // message is an action.
+    Said(Message),
+    // Someone whispered to us, or whispered an action.
+    Whispered(Message),
+    // Something happened in a room we are in.
+    Notice {
+        room: String,
+        time: usize,
+        notice: Notice,
+    },
+    // The answer to one of our commands.
+    Reply(Reply),
+    // A line that isn't a message, like the server's complaint about a
+    // client that hasn't identified.
+    Unknown(String),
+}
+
+pub struct Reply {
+    pub tag: Tag,
+    pub code: StatusCode,
+    // The command this is the answer to.
+    pub command: Command,
+    // What the server said, without the tag: the echoed command, or for
+    // LIST the names asked for and for GETKEY the command followed by the
+    // key.
+    pub body: String,
+}
+
+impl Reply {
+    pub fn ok(&self) -> bool {
+        self.code == StatusCode::Ok
+    }
+}
+
+pub struct Client {
+    conn: Connection,
+    // Bytes of a line that has not been fully received yet.
+    pending: Vec<u8>,
+    // Events read while waiting for a particular reply.
+    queued: VecDeque<Event>,
+    // Commands waiting for a reply, and the last tag used.
+    requests: HashMap<Tag, Command>,
+    last_tag: Tag,
+    // Our name, once the server has accepted one.
+    nick: Option<String>,
+}
+
+impl Client {
+    pub fn connect(addr: &str, security: &Security) -> io::Result<Client> {
+        let conn = Connection::connect(addr, security)?;
+        conn.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        Ok(Client {
+            conn,
+            pending: vec![],
+            queued: VecDeque::new(),
+            requests: HashMap::new(),
+            last_tag: 0,
+            nick: None,
+        })
+    }
+
+    // Our name, once the server has accepted an IDENTIFY or NICK.
+    pub fn nick(&self) -> Option<&str> {
+        self.nick.as_deref()
+    }
+
+    // Sends any command, returning the tag its reply will carry.
+    pub fn send(&mut self, command: Command) -> io::Result<Tag> {
+        self.last_tag += 1;
+        self.conn.write_all(format!("#{} {}\n", self.last_tag, command).as_bytes())?;
+        self.conn.flush()?;
+
+        self.requests.insert(self.last_tag, command);
+        Ok(self.last_tag)
+    }
+
+    pub fn identify(&mut self, nick: &str) -> io::Result<Tag> {
+        self.send(Command::Identify(nick.to_string()))
+    }
+
+    pub fn join(&mut self, room: &str) -> io::Result<Tag> {
+        self.send(Command::Join(room.to_string()))
+    }
+
+    pub fn leave(&mut self, room: &str) -> io::Result<Tag> {
+        self.send(Command::Leave(room.to_string()))
+    }
+
+    pub fn say(&mut self, room: &str, message: &str) -> io::Result<Tag> {
+        self.send(Command::Say(room.to_string(), message.to_string()))
+    }
+
+    // Whispers are sent as they are given; encrypting them is up to the
+    // caller.
+    pub fn whisper(&mut self, to: &str, message: &str) -> io::Result<Tag> {
+        self.send(Command::Whisper(to.to_string(), message.to_string()))
+    }
+
+    pub fn shout(&mut self, message: &str) -> io::Result<Tag> {
+        self.send(Command::Shout(message.to_string()))
+    }
+
+    // Describes something we do, in a room, to someone as `@nick`, or
+    // everywhere as `*`.
+    pub fn action(&mut self, to: &str, text: &str) -> io::Result<Tag> {
+        self.send(Command::Action(to.to_string(), text.to_string()))
+    }
+
+    // Asks for the rooms on the server, or for the people in one.
+    pub fn list(&mut self, room: Option<&str>) -> io::Result<Tag> {
+        self.send(Command::List(room.map(String::from)))
+    }
+
+    pub fn set_nick(&mut self, nick: &str) -> io::Result<Tag> {
+        self.send(Command::Nick(nick.to_string()))
+    }
+
+    pub fn topic(&mut self, room: &str, topic: Option<&str>) -> io::Result<Tag> {
+        self.send(Command::Topic(room.to_string(), topic.map(String::from)))
+    }
+
+    pub fn kick(&mut self, room: &str, nick: &str) -> io::Result<Tag> {
+        self.send(Command::Kick(room.to_string(), nick.to_string()))
+    }
+
+    pub fn whois(&mut self, nick: &str) -> io::Result<Tag> {
+        self.send(Command::Whois(nick.to_string()))
+    }
+
+    // Says why we are away, or with None that we are back.
+    pub fn away(&mut self, message: Option<&str>) -> io::Result<Tag> {
+        self.send(Command::Away(message.map(String::from)))
+    }
+
+    pub fn admin(&mut self, password: &str) -> io::Result<Tag> {
+        self.send(Command::Admin(password.to_string()))
+    }
+
+    // Tells everyone on the server; only allowed after `admin`.
+    pub fn announce(&mut self, message: &str) -> io::Result<Tag> {
+        self.send(Command::Announce(message.to_string()))
+    }
+
+    // Logs in to an operator account. The operator commands below fail
+    // without one.
+    pub fn oper(&mut self, name: &str, password: &str) -> io::Result<Tag> {
+        self.send(Command::Oper(name.to_string(), password.to_string()))
+    }
+
+    pub fn kill(&mut self, nick: &str) -> io::Result<Tag> {
+        self.send(Command::Kill(nick.to_string()))
+    }
+
+    // Bans a range of addresses, like "192.0.2.0/24", or with None asks
+    // for the banned ranges.
+    pub fn ban(&mut self, range: Option<&str>) -> io::Result<Tag> {
+        self.send(Command::Ban(range.map(String::from)))
+    }
+
+    pub fn unban(&mut self, range: &str) -> io::Result<Tag> {
+        self.send(Command::Unban(range.to_string()))
+    }
+
+    pub fn close(&mut self, room: &str) -> io::Result<Tag> {
+        self.send(Command::Close(room.to_string()))
+    }
+
+    pub fn rename(&mut self, nick: &str, new_nick: &str) -> io::Result<Tag> {
+        self.send(Command::Rename(nick.to_string(), new_nick.to_string()))
+    }
+
+    pub fn reload(&mut self) -> io::Result<Tag> {
+        self.send(Command::Reload)
+    }
+
+    pub fn publish_key(&mut self, key: &str) -> io::Result<Tag> {
+        self.send(Command::Key(key.to_string()))
+    }
+
+    pub fn get_key(&mut self, nick: &str) -> io::Result<Tag> {
+        self.send(Command::GetKey(nick.to_string()))
+    }
+
+    pub fn quit(&mut self) -> io::Result<Tag> {
+        self.send(Command::Quit)
+    }
+
+    // Reads from the server once, waiting a little while at most, and
+    // returns the events that arrived. The server closing the connection
+    // is an error.
+    pub fn poll(&mut self) -> io::Result<Vec<Event>> {
+        let mut events: Vec<Event> = self.queued.drain(..).collect();
+
+        let mut buf = [0; 1024];
+        let read = match self.conn.read(&mut buf) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection")),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => 0,
+            Err(e) => return Err(e),
+        };
+        self.pending.extend_from_slice(&buf[..read]);
+
+        // Only complete lines are processed; the remainder waits for the
+        // next read.
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..end + 1).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+
+            if !line.is_empty() {
+                events.push(self.event(line));
+            }
+        }
+
+        Ok(events)
+    }
+
+    // Waits for the reply to the command sent with `tag`, failing with
+    // TimedOut if none comes within REPLY_TIMEOUT. Anything else that
+    // arrives meanwhile is kept for the next poll.
+    pub fn wait_for(&mut self, tag: Tag) -> io::Result<Reply> {
+        let deadline = time::Instant::now() + REPLY_TIMEOUT;
+        let mut others = vec![];
+
+        while time::Instant::now() < deadline {
+            let events = match self.poll() {
+                Ok(events) => events,
+                Err(e) => {
+                    self.queued.extend(others);
+                    return Err(e);
+                },
+            };
+
+            for event in events {
+                match event {
+                    Event::Reply(reply) if reply.tag == tag => {
+                        self.queued.extend(others);
+                        return Ok(reply);
+                    },
+                    event => others.push(event),
+                }
+            }
+        }
+
+        self.queued.extend(others);
+        Err(io::Error::new(io::ErrorKind::TimedOut, format!("no reply to request {}", tag)))
+    }
+
+    // Events as they arrive, waiting for each. Ends after the first error.
+    pub fn events(&mut self) -> Events<'_> {
+        Events {
+            client: self,
+            ready: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    fn event(&mut self, line: &str) -> Event {
+        let m = match Message::try_new(line) {
+            Ok(m) => m,
+            Err(_) => return Event::Unknown(line.to_string()),
+        };
+
+        if m.sender != "server" {
+            if self.nick.as_ref() == Some(&m.room) {
+                return Event::Whispered(m);
+            }
+            return Event::Said(m);
+        }
+
+        if m.room != "server" {
+            return match Notice::parse(&m.body) {
+                Some(notice) => {
+                    // An operator may rename us.
+                    if let Notice::Nick(ref old, ref new) = notice {
+                        if self.nick.as_ref() == Some(old) {
+                            self.nick = Some(new.clone());
+                        }
+                    }
+
+                    Event::Notice { room: m.room, time: m.time, notice }
+                },
+                None => Event::Unknown(line.to_string()),
+            };
+        }
+
+        let (tag, body) = common::split_tag(&m.body);
+        let tag = tag.and_then(|tag| tag.parse::<Tag>().ok());
+        let request = tag.and_then(|tag| self.requests.remove(&tag).map(|command| (tag, command)));
+
+        match (StatusCode::from_code(m.code), request) {
+            (Some(code), Some((tag, command))) => {
+                match command {
+                    Command::Identify(ref name) |
+                    Command::Nick(ref name) if code == StatusCode::Ok => self.nick = Some(name.clone()),
+                    _ => (),
+                }
+
+                Event::Reply(Reply { tag, code, command, body: body.to_string() })
+            },
+            _ => Event::Unknown(line.to_string()),
+        }
+    }
+}
+
+pub struct Events<'a> {
+    client: &'a mut Client,
+    ready: VecDeque<Event>,
+    failed: bool,
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<io::Result<Event>> {
+        while self.ready.is_empty() && !self.failed {
+            match self.client.poll() {
+                Ok(events) => self.ready.extend(events),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+
+        self.ready.pop_front().map(Ok)
+    }
+}